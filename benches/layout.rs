@@ -0,0 +1,266 @@
+#[macro_use]
+extern crate criterion;
+extern crate dadabass;
+
+use criterion::{black_box, BenchmarkId, Criterion};
+use dadabass::arena::ArenaTree;
+use dadabass::avl::{NoRebalance, Tree};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+// Counts every allocation (and reallocation), so we can see how many a bulk insert
+// makes, not just how long it takes.
+struct Counting;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+// Compares the boxed Tree against the ArenaTree, which keeps its nodes in one Vec, on
+// inserting a batch of values and then looking every one of them up again. The values
+// come from a little linear congruential generator, so every run inserts the same
+// values in the same, scattered, order.
+fn values(n: usize) -> Vec<u64> {
+    let mut state: u64 = 0x2545_f491_4f6c_dd1d;
+    (0..n).map(|_| {
+        state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+        state >> 16
+    }).collect()
+}
+
+fn insert(c: &mut Criterion) {
+    let mut group = c.benchmark_group("insert");
+    for &n in &[1_000, 100_000] {
+        let values = values(n);
+        group.bench_with_input(BenchmarkId::new("boxed", n), &values, |b, values| b.iter(|| {
+            let mut tree = Tree::new();
+            for &value in values {
+                tree.insert(value);
+            }
+            tree
+        }));
+        group.bench_with_input(BenchmarkId::new("arena", n), &values, |b, values| b.iter(|| {
+            let mut tree = ArenaTree::new();
+            for &value in values {
+                tree.insert(value);
+            }
+            tree
+        }));
+    }
+    group.finish();
+}
+
+fn lookup(c: &mut Criterion) {
+    let mut group = c.benchmark_group("lookup");
+    for &n in &[1_000, 100_000] {
+        let values = values(n);
+        let mut boxed = Tree::new();
+        let mut arena = ArenaTree::new();
+        for &value in &values {
+            boxed.insert(value);
+            arena.insert(value);
+        }
+        group.bench_with_input(BenchmarkId::new("boxed", n), &values, |b, values| b.iter(|| {
+            values.iter().filter(|value| boxed.contains(black_box(value))).count()
+        }));
+        group.bench_with_input(BenchmarkId::new("arena", n), &values, |b, values| b.iter(|| {
+            values.iter().filter(|value| arena.contains(black_box(value))).count()
+        }));
+    }
+    group.finish();
+}
+
+// What rebalancing costs and what it buys: inserting values, and then looking them
+// all up, with and without the tree rebalancing itself. Scattered values keep the
+// unbalanced tree from turning into a list, but it still ends up about twice as tall
+// as the AVL tree. Ascending values are its worst case, a list where every insert and
+// lookup walks all the way down, so there are fewer of those to keep it bearable.
+fn policy(c: &mut Criterion) {
+    let mut group = c.benchmark_group("policy");
+    let inputs = [("scattered", values(100_000)), ("ascending", (0..10_000).collect())];
+    for &(order, ref values) in &inputs {
+        let n = values.len();
+        let mut balanced = Tree::new();
+        let mut unbalanced = Tree::<u64, NoRebalance>::with_policy();
+        for &value in values {
+            balanced.insert(value);
+            unbalanced.insert(value);
+        }
+        println!("policy: {} {} values make an AVL tree of height {}, and an unbalanced one of height {}",
+                 n, order, balanced.height(), unbalanced.height());
+
+        group.bench_with_input(BenchmarkId::new(format!("insert avl {}", order), n), values, |b, values| b.iter(|| {
+            let mut tree = Tree::new();
+            for &value in values {
+                tree.insert(value);
+            }
+            tree
+        }));
+        group.bench_with_input(BenchmarkId::new(format!("insert unbalanced {}", order), n), values, |b, values| b.iter(|| {
+            let mut tree = Tree::<u64, NoRebalance>::with_policy();
+            for &value in values {
+                tree.insert(value);
+            }
+            tree
+        }));
+        group.bench_with_input(BenchmarkId::new(format!("lookup avl {}", order), n), values, |b, values| b.iter(|| {
+            values.iter().filter(|value| balanced.contains(black_box(value))).count()
+        }));
+        group.bench_with_input(BenchmarkId::new(format!("lookup unbalanced {}", order), n), values, |b, values| b.iter(|| {
+            values.iter().filter(|value| unbalanced.contains(black_box(value))).count()
+        }));
+    }
+    group.finish();
+}
+
+// Inserting a batch into an arena with and without reserving room for it first. The
+// allocation counts are printed once before timing, since criterion only reports time.
+fn reserve(c: &mut Criterion) {
+    let mut group = c.benchmark_group("reserve");
+    let n = 100_000;
+    let values = values(n);
+    let load = |reserve: bool| {
+        let mut tree = ArenaTree::new();
+        if reserve {
+            tree.reserve(values.len());
+        }
+        for &value in &values {
+            tree.insert(value);
+        }
+        tree
+    };
+
+    for &reserved in &[false, true] {
+        let before = ALLOCATIONS.load(Ordering::Relaxed);
+        drop(load(reserved));
+        println!("reserve: {} allocations inserting {} values with reserve {}", ALLOCATIONS.load(Ordering::Relaxed) - before, n, reserved);
+        group.bench_with_input(BenchmarkId::new(if reserved { "reserved" } else { "growing" }, n), &reserved, |b, &reserved| b.iter(|| load(reserved)));
+    }
+    group.finish();
+}
+
+// Building a tree, throwing it away and building another, over and over, as a boxed
+// Tree and as an ArenaTree that gets cleared each time. The boxed tree allocates every
+// node again each round, while the arena only allocates the first time. Again the
+// allocation counts are printed before timing.
+fn clear(c: &mut Criterion) {
+    let mut group = c.benchmark_group("clear");
+    let (n, rounds) = (10_000, 20);
+    let values = values(n);
+    let boxed = || {
+        for _ in 0..rounds {
+            let mut tree = Tree::new();
+            for &value in &values {
+                tree.insert(value);
+            }
+        }
+    };
+    let mut arena = ArenaTree::new();
+    let mut cleared = || {
+        for _ in 0..rounds {
+            arena.clear();
+            for &value in &values {
+                arena.insert(value);
+            }
+        }
+    };
+
+    let before = ALLOCATIONS.load(Ordering::Relaxed);
+    boxed();
+    println!("clear: {} allocations building {} boxed trees of {} values", ALLOCATIONS.load(Ordering::Relaxed) - before, rounds, n);
+    let before = ALLOCATIONS.load(Ordering::Relaxed);
+    cleared();
+    println!("clear: {} allocations building {} arena trees of {} values, clearing each time", ALLOCATIONS.load(Ordering::Relaxed) - before, rounds, n);
+    let before = ALLOCATIONS.load(Ordering::Relaxed);
+    cleared();
+    println!("clear: {} allocations doing it again in the same arena", ALLOCATIONS.load(Ordering::Relaxed) - before);
+
+    group.bench_function(BenchmarkId::new("boxed", n), |b| b.iter(|| boxed()));
+    group.bench_function(BenchmarkId::new("arena", n), |b| b.iter(|| cleared()));
+    group.finish();
+}
+
+// Building a boxed tree out of a million values by inserting them one at a time, with
+// all the rotations that takes, against collecting them, which sorts once and builds
+// the tree from that without rotating anything.
+fn from_iter(c: &mut Criterion) {
+    let mut group = c.benchmark_group("from_iter");
+    group.sample_size(10);
+    let n = 1_000_000;
+    let values = values(n);
+    group.bench_with_input(BenchmarkId::new("insert", n), &values, |b, values| b.iter(|| {
+        let mut tree = Tree::new();
+        for &value in values {
+            tree.insert(value);
+        }
+        tree
+    }));
+    group.bench_with_input(BenchmarkId::new("collect", n), &values, |b, values| b.iter(|| {
+        values.iter().cloned().collect::<Tree<u64>>()
+    }));
+    group.finish();
+}
+
+// Adding a big sorted batch to a tree of the same size, one insert at a time, against
+// merging it in as a slice.
+fn merge_sorted_slice(c: &mut Criterion) {
+    let mut group = c.benchmark_group("merge_sorted_slice");
+    group.sample_size(10);
+    let n = 100_000;
+    let all = values(2 * n);
+    let tree: Tree<u64> = all[..n].iter().cloned().collect();
+    let mut batch = all[n..].to_vec();
+    batch.sort();
+    batch.dedup();
+    group.bench_with_input(BenchmarkId::new("insert", n), &batch, |b, batch| b.iter(|| {
+        let mut tree = tree.clone();
+        for &value in batch {
+            tree.insert(value);
+        }
+        tree
+    }));
+    group.bench_with_input(BenchmarkId::new("merge", n), &batch, |b, batch| b.iter(|| {
+        let mut tree = tree.clone();
+        tree.merge_sorted_slice(batch);
+        tree
+    }));
+    group.finish();
+}
+
+// Building a big tree from sorted values on one thread, and with its subtrees spread
+// across rayon's threads.
+#[cfg(feature = "rayon")]
+fn from_sorted_par(c: &mut Criterion) {
+    let mut group = c.benchmark_group("from_sorted_par");
+    group.sample_size(10);
+    let n = 4_000_000;
+    let mut values = values(n);
+    values.sort();
+    values.dedup();
+    group.bench_with_input(BenchmarkId::new("sequential", n), &values, |b, values| b.iter(|| Tree::from_sorted(values.clone())));
+    group.bench_with_input(BenchmarkId::new("parallel", n), &values, |b, values| b.iter(|| Tree::from_sorted_par(values.clone())));
+    group.finish();
+}
+
+#[cfg(not(feature = "rayon"))]
+fn from_sorted_par(_: &mut Criterion) {}
+
+criterion_group!(benches, insert, lookup, policy, reserve, clear, from_iter, merge_sorted_slice, from_sorted_par);
+criterion_main!(benches);
@@ -0,0 +1,282 @@
+use alloc::vec::Vec;
+use core::cmp::max;
+use core::mem;
+use avl::taller;
+
+// The nodes of a Tree each get their own allocation, so a big tree ends up scattered
+// all over the heap, and walking down it is a cache miss at nearly every step. An
+// ArenaTree instead keeps every node in one Vec, and children are indices into it
+// rather than Boxes. Nodes that were inserted around the same time sit next to each
+// other in memory, and there's one allocation to grow rather than one per value. The
+// nodes aren't any smaller, though: an Option<u32> takes 8 bytes, since the u32 has
+// no spare value to say None with, whereas an Option<Box> fits in the pointer's 8
+// bytes by using null for None.
+//
+// The balancing logic is exactly the same as the Tree's. The one thing that changes
+// is how rotations hand back the new root of a subtree: rather than swapping node
+// contents around so the parent's pointer stays put, we just return the index of the
+// new root, and the parent points at that instead.
+//
+// Nodes are never freed, so for now there's no way to remove values. That's the
+// usual tradeoff for arenas, and makes this a good fit for trees that are built up
+// once and then mostly searched.
+struct Node<V: Ord> {
+    metadata: (i8, i8),
+    value: V,
+    left: Option<u32>,
+    right: Option<u32>
+}
+
+pub struct ArenaTree<V: Ord> {
+    nodes: Vec<Node<V>>,
+    root: Option<u32>
+}
+
+impl <V: Ord> Default for ArenaTree<V> {
+    fn default() -> Self {
+        ArenaTree::new()
+    }
+}
+
+impl <V: Ord> ArenaTree<V> {
+    pub fn new() -> Self {
+        ArenaTree {nodes: Vec::new(), root: None}
+    }
+
+    // Room for this many values before the arena has to grow.
+    pub fn with_capacity(capacity: usize) -> Self {
+        ArenaTree {nodes: Vec::with_capacity(capacity), root: None}
+    }
+
+    // Makes room for at least this many more values, so a big batch of inserts grows
+    // the arena once up front, instead of every time it fills up along the way. Each
+    // time a Vec grows it copies everything into a new allocation twice the size, so
+    // without this a batch of n values copies about n nodes more than it needs to. The
+    // boxed Tree has no equivalent, since it allocates every node on its own anyway.
+    pub fn reserve(&mut self, additional: usize) {
+        self.nodes.reserve(additional);
+    }
+
+    // Empties the tree, but keeps the arena's allocation, like Vec::clear, so building
+    // another tree of about the same size in it doesn't allocate at all. The boxed Tree
+    // can't do this, since its nodes are each allocated on their own, and clearing it
+    // frees every one of them.
+    pub fn clear(&mut self) {
+        self.nodes.clear();
+        self.root = None;
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.nodes.capacity()
+    }
+
+    // Roughly how many bytes the tree takes up: the ArenaTree itself, plus the whole of
+    // the Vec's allocation, including any room it has reserved but not yet used. It's
+    // only an estimate, since it leaves out the allocator's own bookkeeping and
+    // anything the values own on the heap. Unlike the boxed Tree, that's one
+    // allocation however many values there are, and it's O(1) to work out.
+    pub fn memory_usage(&self) -> usize {
+        mem::size_of::<ArenaTree<V>>() + self.nodes.capacity() * mem::size_of::<Node<V>>()
+    }
+
+    // Every node in the arena holds a value, so there's no need to count separately.
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    pub fn contains(&self, value: &V) -> bool {
+        let mut next = self.root;
+        while let Some(index) = next {
+            let node = &self.nodes[index as usize];
+            if *value < node.value {
+                next = node.left;
+            } else if *value > node.value {
+                next = node.right;
+            } else {
+                return true
+            }
+        }
+        false
+    }
+
+    pub fn insert(&mut self, value: V) -> bool {
+        let root = self.root;
+        match self.insert_into(root, value) {
+            Some(root) => {
+                self.root = Some(root);
+                true
+            }
+            None => false
+        }
+    }
+
+    // Inserts into the subtree at the given index, returning the index of the subtree's
+    // root afterwards (which a rotation may have changed), or None if the value was
+    // already there.
+    fn insert_into(&mut self, tree: Option<u32>, value: V) -> Option<u32> {
+        let index = match tree {
+            Some(index) => index,
+            None => {
+                assert!(self.nodes.len() < u32::MAX as usize, "an arena tree can hold at most 2^32 - 1 values");
+                self.nodes.push(Node {metadata: (0, 0), value: value, left: None, right: None});
+                return Some((self.nodes.len() - 1) as u32)
+            }
+        };
+
+        if value < self.nodes[index as usize].value {
+            let left = self.nodes[index as usize].left;
+            let left = self.insert_into(left, value)?;
+            self.nodes[index as usize].left = Some(left);
+        } else if value > self.nodes[index as usize].value {
+            let right = self.nodes[index as usize].right;
+            let right = self.insert_into(right, value)?;
+            self.nodes[index as usize].right = Some(right);
+        } else {
+            return None // we don't allow duplicates.
+        }
+        self.fix_metadata(index);
+        Some(self.balance(index))
+    }
+
+    fn height(&self, tree: Option<u32>) -> i8 {
+        match tree {
+            Some(index) => {
+                let (left, right) = self.nodes[index as usize].metadata;
+                taller(max(left, right))
+            }
+            None => 0
+        }
+    }
+
+    fn fix_metadata(&mut self, index: u32) {
+        let (left, right) = (self.nodes[index as usize].left, self.nodes[index as usize].right);
+        self.nodes[index as usize].metadata = (self.height(left), self.height(right));
+    }
+
+    fn rotate_left(&mut self, index: u32) -> u32 {
+        let right = self.nodes[index as usize].right.expect("rotating left requires a right child");
+        self.nodes[index as usize].right = self.nodes[right as usize].left;
+        self.fix_metadata(index);
+        self.nodes[right as usize].left = Some(index);
+        self.fix_metadata(right);
+        right
+    }
+
+    fn rotate_right(&mut self, index: u32) -> u32 {
+        let left = self.nodes[index as usize].left.expect("rotating right requires a left child");
+        self.nodes[index as usize].left = self.nodes[left as usize].right;
+        self.fix_metadata(index);
+        self.nodes[left as usize].right = Some(index);
+        self.fix_metadata(left);
+        left
+    }
+
+    // The same rotations as AvlTree::balance: if the child on the tall side leans the
+    // other way, it gets rotated first, turning the double rotation into a single one.
+    fn balance(&mut self, index: u32) -> u32 {
+        let (left_height, right_height) = self.nodes[index as usize].metadata;
+        let difference = left_height - right_height;
+
+        // if this fails, all hope is lost.
+        assert!((-2..=2).contains(&difference));
+
+        if difference == 2 {
+            let left = self.nodes[index as usize].left.expect("the taller side has a child");
+            let (left_left, left_right) = self.nodes[left as usize].metadata;
+            if left_left < left_right {
+                let left = self.rotate_left(left);
+                self.nodes[index as usize].left = Some(left);
+            }
+            self.rotate_right(index)
+        } else if difference == -2 {
+            let right = self.nodes[index as usize].right.expect("the taller side has a child");
+            let (right_left, right_right) = self.nodes[right as usize].metadata;
+            if right_left > right_right {
+                let right = self.rotate_right(right);
+                self.nodes[index as usize].right = Some(right);
+            }
+            self.rotate_left(index)
+        } else {
+            index
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Inserting should add exactly the values a BTreeSet would, and leave every node
+    // ordered, balanced and with the right heights.
+    #[quickcheck]
+    fn arena_property(values: Vec<i32>, lookups: Vec<i32>) -> bool {
+        let mut tree = ArenaTree::new();
+        let mut reference = std::collections::BTreeSet::new();
+        let inserted_ok = values.iter().all(|&value| tree.insert(value) == reference.insert(value));
+
+        inserted_ok && tree.len() == reference.len() && valid(&tree, tree.root, None, None) &&
+            lookups.iter().chain(values.iter()).all(|value| tree.contains(value) == reference.contains(value))
+    }
+
+    #[test]
+    fn reserve_grows_capacity_up_front() {
+        let mut tree = ArenaTree::new();
+        tree.reserve(1000);
+        let capacity = tree.capacity();
+        assert!(capacity >= 1000);
+
+        for value in 0..1000 {
+            tree.insert(value);
+        }
+        assert_eq!(capacity, tree.capacity());
+    }
+
+    // The arena's estimate covers its spare capacity too.
+    #[test]
+    fn memory_usage_counts_capacity() {
+        let mut tree = ArenaTree::with_capacity(100);
+        tree.insert(1u64);
+        assert_eq!(mem::size_of::<ArenaTree<u64>>() + tree.capacity() * mem::size_of::<Node<u64>>(), tree.memory_usage());
+        assert!(tree.memory_usage() >= 100 * mem::size_of::<Node<u64>>());
+    }
+
+    // Clearing should leave an empty tree that works as normal, without giving up any
+    // of its capacity.
+    #[test]
+    fn clear_keeps_capacity() {
+        let mut tree = ArenaTree::new();
+        for value in 0..1000 {
+            tree.insert(value);
+        }
+        let capacity = tree.capacity();
+
+        tree.clear();
+        assert!(tree.is_empty());
+        assert!(!tree.contains(&5));
+        assert_eq!(capacity, tree.capacity());
+
+        for value in (0..1000).rev() {
+            tree.insert(value);
+        }
+        assert_eq!(capacity, tree.capacity());
+        assert_eq!(1000, tree.len());
+        assert!(valid(&tree, tree.root, None, None));
+    }
+
+    fn valid(tree: &ArenaTree<i32>, index: Option<u32>, lower: Option<i32>, upper: Option<i32>) -> bool {
+        match index {
+            Some(index) => {
+                let node = &tree.nodes[index as usize];
+                node.metadata == (tree.height(node.left), tree.height(node.right)) &&
+                    node.metadata.0 - node.metadata.1 <= 1 && node.metadata.0 - node.metadata.1 >= -1 &&
+                    lower.map_or(true, |lower| node.value > lower) && upper.map_or(true, |upper| node.value < upper) &&
+                    valid(tree, node.left, lower, Some(node.value)) && valid(tree, node.right, Some(node.value), upper)
+            }
+            None => true
+        }
+    }
+}
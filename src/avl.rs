@@ -1,256 +1,509 @@
-extern crate quickcheck;
-extern crate rand;
-extern crate std;
-
-use std::mem;
-use quickcheck::Arbitrary;
-use quickcheck::Gen;
-
-// This property ensures that all elements to the left of the node we're handed are less
-// than the value that node contains, and all elements to the right greater than. Equal
-// is not handled as duplicates are not allowed.
-#[quickcheck]
-fn ordering_property(bt: BinaryTree<i32, (i8, i8)>) -> bool {
-    match bt {
-        BinaryTree {metadata: _, value, left: Some(ref left), right: Some(ref right)} => {
-            return left.iter().all(|t| value > t.value) && right.iter().all(|t| value < t.value)
-        },
-        BinaryTree {metadata: _, value, left: None, right: Some(ref right)} => {
-            return right.iter().all(|t| value < t.value)
-        },
-        BinaryTree {metadata: _, value, left: Some(ref left), right: None} => {
-            return left.iter().all(|t| value > t.value)
-        },
-        _ => true
-    }
-}
-
-// This implementation of an AVL tree tracks height of left and right children as a
-// 2-tuple of i8s that represent the height of the respective children.
+use alloc::alloc::AllocError;
+use alloc::boxed::Box;
+use alloc::collections::BinaryHeap;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::borrow::Borrow;
+#[cfg(feature = "rotation-count")]
+use core::cell::Cell;
+use core::cmp::max;
+use core::cmp::Ordering;
+use core::cmp::Reverse;
+use core::convert::Infallible;
+use core::fmt;
+use core::fmt::Debug;
+use core::iter::FromIterator;
+use core::iter::Peekable;
+use core::marker::PhantomData;
+use core::mem;
+use core::mem::MaybeUninit;
+use core::ops::Bound;
+use core::ops::ControlFlow;
+use core::ops::RangeBounds;
+use core::ptr;
+use persistent::PersistentTree;
 
-// This property recursively walks the tree and verifies that the height metadata is
-// in correspondence with the recursive calculation of height.
-#[quickcheck]
-fn height_is_maintained(bt: AvlTree<i32>) -> bool {
-    match bt {
-        BinaryTree {
-            metadata: (ref left_height, ref right_height), value: _,
-            left: Some(box BinaryTree {metadata: (ref left_left_height, ref left_right_height), ..}),
-            right: Some(box BinaryTree {metadata: (ref right_left_height, ref right_right_height), ..})}
-        => {
-            *left_height == std::cmp::max(*left_left_height, *left_right_height) + 1 && *right_height == std::cmp::max(*right_left_height, *right_right_height) + 1
-        },
-        BinaryTree {
-            metadata: (ref left_height, ref right_height), value: _,
-            right: Some(box BinaryTree {metadata: (ref right_left_height, ref right_right_height), ..}),
-            left: None}
-        => {
-            *right_height == std::cmp::max(*right_left_height, *right_right_height) + 1 && *left_height == 0
-        },
-        BinaryTree {
-            metadata: (ref left_height, ref right_height), value: _,
-            left: Some(box BinaryTree {metadata: (ref left_left_height, ref left_right_height), ..}),
-            right: None}
-        => {
-            *left_height == std::cmp::max(*left_left_height, *left_right_height) + 1 && *right_height == 0
-        },
-        BinaryTree {metadata: (ref left_height, ref right_height), value: _, left: None, right: None} => {
-            *left_height == 0 && *right_height == 0
-        }
-    }
-}
-
-// This property verifies that, for the node we're given, the height metadata never
-// differs by more than 1. This is the definition of a tree being balanced.
-#[quickcheck]
-fn balance_property(bt: BinaryTree<i32, (i8, i8)>) -> bool {
-    match bt {
-        BinaryTree {metadata, ..} => ( metadata.0 - metadata.1 ) <= 1 && ( metadata.0 - metadata.1 ) >= -1
-    }
-}
-
-#[derive(Debug,Clone)]
-struct BinaryTree<V: Ord+Copy, M> {
+#[derive(Clone)]
+struct BinaryTree<V: Ord, M> {
         metadata: M,
+        size: usize,
         value: V,
         left: Option<Box<BinaryTree<V, M>>>,
         right: Option<Box<BinaryTree<V, M>>>
 }
 
-// An arbitrary tree is formed by starting with an empty tree and inserting an arbitrary
-// number of arbitrary values. Generating the tree any other way would defeat the purpose
-// of the property based tests.
-impl Arbitrary for BinaryTree<i32, (i8, i8)> {
-    fn arbitrary<G: Gen>(g: &mut G) -> Self {
-        let mut tree = BinaryTree {metadata: (0, 0), value: g.gen_range(-1000,1000), left: None, right: None};
-        while g.gen() {
-            tree.insert(g.gen_range(-1000,1000));
+// The derived Debug nests every child inside its parent, which is unreadable for all
+// but the tiniest trees. Instead, we draw the tree on its side: the root is at the
+// left margin, each level is indented a bit further, and right children are printed
+// above their parents while left children are printed below. Tilt your head to the
+// left and it looks like a tree. Every node is printed with its metadata, which for
+// AVL trees is (left_height, right_height), so it's easy to eyeball the balance.
+// Inserting 1 through 8 in order, for instance, draws as:
+//
+//             8 (0, 0)
+//         7 (0, 1)
+//     6 (1, 2)
+//         5 (0, 0)
+// 4 (2, 3)
+//         3 (0, 0)
+//     2 (1, 1)
+//         1 (0, 0)
+//
+// This is a reverse in-order traversal (right, node, left) using an explicit stack,
+// so printing a deep tree doesn't use a stack frame per level.
+impl <V: Ord+Debug, M: Debug> Debug for BinaryTree<V, M> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut stack: Vec<(&BinaryTree<V, M>, usize)> = Vec::new();
+        let mut next = Some((self, 0));
+        loop {
+            while let Some((tree, depth)) = next {
+                stack.push((tree, depth));
+                next = tree.right.as_ref().map(|right| (&**right, depth + 1));
+            }
+            match stack.pop() {
+                Some((tree, depth)) => {
+                    writeln!(f, "{:indent$}{:?} {:?}", "", tree.value, tree.metadata, indent = depth * 4)?;
+                    next = tree.left.as_ref().map(|left| (&**left, depth + 1));
+                }
+                None => return Ok(())
+            }
         }
-        tree
     }
 }
 
-// The iterator stuff is only used in the quickcheck properties. Specifically for
-// checking ordering.
-impl <'a, V: Ord+Copy+Clone+Send, M: Copy+Clone+Send> BinaryTree<V, M> {
-    #[allow(dead_code)]
-    fn iter(&'a self) -> BinaryTreeIterator<'a, V, M> {
-        BinaryTreeIterator {to_visit: vec![&self]}
+// This implementation of an AVL tree tracks height of left and right children as a
+// 2-tuple of i8s that represent the height of the respective children.
+type AvlTree<V> = BinaryTree<V, (i8, i8)>;
+
+// The nodes above can't represent a tree with nothing in it, and which node is the
+// root changes out from under us whenever we rotate at the top. So the tree users
+// actually hold is this wrapper, which owns the (possibly absent) root and keeps
+// track of how many elements are in it, and which balance policy it follows.
+pub struct Tree<V: Ord, P: BalancePolicy = Avl> {
+    root: Option<Box<AvlTree<V>>>,
+    len: usize,
+    #[cfg(feature = "rotation-count")]
+    rotations: u64,
+    policy: PhantomData<P>
+}
+
+// Whether a tree rebalances itself as values go in. Avl, the default, is what every
+// tree normally does. NoRebalance skips balancing altogether, leaving a plain binary
+// search tree shaped by whatever order its values arrived in, which shows what all
+// the balancing buys: insert values in ascending order and it's a linked list, where
+// every lookup walks the whole thing. Since nothing else about the tree would still
+// hold, a NoRebalance tree can only do the few things that don't depend on it being
+// balanced, which are inserting, looking values up and iterating. It doesn't keep
+// track of heights either, since a list of any length would overflow them, so its
+// height() walks the whole tree. Inserts still recurse a level at a time, though, so
+// a list tens of thousands of values long needs a bigger stack than a test thread's.
+// It's for experiments, not real work.
+//
+// Every insert goes the same way whatever the policy, and on the way back up, once
+// the new value is in somewhere below a node, it hands the node to the policy's
+// balance to do whatever the policy does.
+pub trait BalancePolicy {
+    const REBALANCES: bool;
+
+    fn balance<N: Rebalance>(node: &mut N);
+}
+
+// What a balance policy can do to a node. The nodes themselves are private, so this
+// is all a policy gets to see of them.
+pub trait Rebalance {
+    // Rotates the node's subtree back into AVL balance, if the insert tipped it over.
+    fn restore_balance(&mut self);
+
+    // Resets the heights of the node's children to zero, for trees that don't keep
+    // them.
+    fn forget_heights(&mut self);
+}
+
+pub struct Avl;
+
+pub struct NoRebalance;
+
+impl BalancePolicy for Avl {
+    const REBALANCES: bool = true;
+
+    fn balance<N: Rebalance>(node: &mut N) {
+        node.restore_balance();
     }
 }
 
-#[allow(dead_code)]
-struct BinaryTreeIterator<'a, V: 'a+Ord+Copy+Clone+Send, M: 'a+Copy+Clone+Send> {
-    to_visit: Vec<&'a BinaryTree<V, M>>
+impl BalancePolicy for NoRebalance {
+    const REBALANCES: bool = false;
+
+    fn balance<N: Rebalance>(node: &mut N) {
+        node.forget_heights();
+    }
 }
 
-impl <'a, V: 'a+Ord+Copy+Clone+Send, M: 'a+Copy+Clone+Send> Iterator for BinaryTreeIterator<'a, V, M> {
-    type Item = &'a BinaryTree<V, M>;
+// With the rotation-count feature on, every rotation bumps a counter, so we can see
+// how much rebalancing different ways of building a tree cost. Rotations happen deep
+// down in the nodes, which don't know which tree they belong to, so they're counted
+// per thread, and each operation on a Tree moves whatever got counted while it ran
+// over to the tree's own total before returning. Operations don't run concurrently on
+// one thread, so whatever's pending at the end of one was all done by it. Without the
+// feature this all compiles away, and the nodes don't pay for it.
+#[cfg(feature = "rotation-count")]
+thread_local!(static PENDING_ROTATIONS: Cell<u64> = Cell::new(0));
 
-    // Do depth first search as an iterator.
-    fn next(&mut self) -> Option<&'a BinaryTree<V, M>> {
-        let ret = self.to_visit.pop();
-        match ret {
-            Some(&BinaryTree {left: Some(ref left), right: None, ..}) => {self.to_visit.push(left)},
-            Some(&BinaryTree {left: None, right: Some(ref right), ..}) => {self.to_visit.push(right)},
-            Some(&BinaryTree {left: Some(ref left), right: Some(ref right), ..}) => {
-                self.to_visit.push(left);
-                self.to_visit.push(right)
-            },
-            _ => ()
+#[cfg(feature = "rotation-count")]
+fn count_rotation() {
+    PENDING_ROTATIONS.with(|pending| pending.set(pending.get() + 1));
+}
+
+#[cfg(not(feature = "rotation-count"))]
+fn count_rotation() {}
+
+// Trees that don't keep a count of their own, like AugmentedTree, throw away whatever
+// their rotations added, so it isn't put down to the next Tree that changes.
+#[cfg(feature = "rotation-count")]
+fn discard_rotations() {
+    PENDING_ROTATIONS.with(|pending| pending.set(0));
+}
+
+#[cfg(not(feature = "rotation-count"))]
+fn discard_rotations() {}
+
+// Heights are stored as i8s, which keeps the metadata small, and is plenty: an AVL
+// tree of height h has at least fib(h + 2) - 1 nodes, so the maximum supported height
+// of 127 is out of reach of anything with fewer than about 4 * 10^26 values in it.
+// Still, if something goes wrong (a bug in balancing, say) the heights could run
+// away, and in release builds adding to an i8 silently wraps around, which would
+// turn into wildly wrong balancing decisions. So whenever we work out the height of
+// a node from the height of its child, we check for overflow and panic instead.
+pub(crate) fn taller(height: i8) -> i8 {
+    height.checked_add(1).expect("the tree is taller than the maximum supported height of 127")
+}
+
+// The tallest an AVL tree with this many values can possibly be. The fewest values a
+// tree of height h can have is one for the root, plus the fewest for heights h - 1
+// and h - 2 under it, since its subtrees can differ by one level but no more. So we
+// count up through those minimums until the next one is more than we have. It grows
+// like the Fibonacci numbers, so the bound comes out at about 1.44 log2(n).
+fn height_bound(len: usize) -> usize {
+    let (mut height, mut fewest, mut fewest_below) = (0, 0usize, 0usize);
+    loop {
+        // If the next minimum doesn't even fit in a usize, there can't be that many.
+        let next = match fewest.checked_add(fewest_below).and_then(|sum| sum.checked_add(1)) {
+            Some(next) if next <= len => next,
+            _ => return height
+        };
+        fewest_below = fewest;
+        fewest = next;
+        height += 1;
+    }
+}
+
+// The height of a subtree, as recorded in its parent's metadata. A missing child
+// has height 0, and a node is 1 + max(left_height, right_height), where left_height
+// and right_height are the values stored in that node's own metadata.
+fn child_height(metadata: Option<&(i8, i8)>) -> i8 {
+    match metadata {
+        Some(&(left_height, right_height)) => taller(max(left_height, right_height)),
+        None => 0
+    }
+}
+
+fn height<V: Ord, M: Heights>(tree: &Option<Box<BinaryTree<V, M>>>) -> i8 {
+    child_height(tree.as_ref().map(|tree| tree.metadata.heights()))
+}
+
+// How much taller a node's left side is than its right, which balancing keeps
+// between -1 and 1.
+fn lean<M: Heights>(metadata: &M) -> i8 {
+    let &(left_height, right_height) = metadata.heights();
+    left_height - right_height
+}
+
+// How many values are in a subtree. Every node keeps track of the size of the subtree
+// it's the root of, which is what lets us find values by their position in sorted
+// order without walking through everything before them.
+fn size<V: Ord, M>(tree: &Option<Box<BinaryTree<V, M>>>) -> usize {
+    match *tree {
+        Some(ref tree) => tree.size,
+        None => 0
+    }
+}
+
+// What trying to insert a value into a subtree did. Either there was already an equal
+// value there, and whichever of the two didn't end up in the tree is handed back, or
+// it was added, in which case the subtree may or may not have grown a level taller.
+// The caller needs the first to keep count of the values, and the second to keep its
+// own heights right. Either way, it also says where the value is in the subtree's
+// sorted order, which is how many of the subtree's values are less than it. That's
+// something rotations can't change, so it's still right once everything is balanced
+// again, which makes it a way to find the value after the insert has moved it about.
+#[derive(Debug, PartialEq, Eq)]
+enum InsertOutcome<V> {
+    Duplicate {value: V, position: usize},
+    Inserted {grew: bool, position: usize}
+}
+
+// The metadata a node keeps is a summary of its whole subtree, worked out from the
+// summaries of its children and its own value. Anything that can be summarised that
+// way can be kept up to date for free whenever the tree changes shape, since all the
+// tree has to do is recompute the summary of every node whose children changed, which
+// it already does to keep the heights right. The AVL heights are one such summary,
+// but so is, say, the smallest value of some secondary key anywhere in the subtree,
+// which is what would let a search find the element of a range with the smallest
+// secondary key without looking at every element in it.
+//
+// A missing child has no summary at all, which is why combine takes Options: not
+// every summary has a sensible value for an empty subtree. The children's summaries
+// are borrowed rather than moved, since they stay where they are, in the children.
+//
+// An AugmentedTree keeps any summary like this, of the user's own, alongside the
+// heights it needs for balancing.
+pub trait Augment<V> {
+    // The summary of a node with no children.
+    fn leaf(value: &V) -> Self;
+
+    // The summary of a node, given the summaries of its children.
+    fn combine(left: Option<&Self>, value: &V, right: Option<&Self>) -> Self;
+}
+
+// Whatever else a node keeps, the AVL logic needs the heights of its children in
+// among it, and this is how it finds them.
+trait Heights {
+    fn heights(&self) -> &(i8, i8);
+
+    fn heights_mut(&mut self) -> &mut (i8, i8);
+}
+
+impl Heights for (i8, i8) {
+    fn heights(&self) -> &(i8, i8) {
+        self
+    }
+
+    fn heights_mut(&mut self) -> &mut (i8, i8) {
+        self
+    }
+}
+
+// The AVL metadata, (left_height, right_height). For each side, the height is that of
+// the child on that side, if there is one.
+impl <V> Augment<V> for (i8, i8) {
+    fn leaf(_: &V) -> Self {
+        (0, 0)
+    }
+
+    fn combine(left: Option<&(i8, i8)>, _: &V, right: Option<&(i8, i8)>) -> Self {
+        (child_height(left), child_height(right))
+    }
+}
+
+// The metadata of an AugmentedTree's nodes: the AVL heights, and the user's own
+// summary of the subtree, each worked out from the children's.
+#[derive(Clone, Debug)]
+struct Augmented<A> {
+    heights: (i8, i8),
+    summary: A
+}
+
+impl <V, A: Augment<V>> Augment<V> for Augmented<A> {
+    fn leaf(value: &V) -> Self {
+        Augmented {heights: (0, 0), summary: A::leaf(value)}
+    }
+
+    fn combine(left: Option<&Augmented<A>>, value: &V, right: Option<&Augmented<A>>) -> Self {
+        Augmented {
+            heights: (child_height(left.map(|left| &left.heights)), child_height(right.map(|right| &right.heights))),
+            summary: A::combine(left.map(|left| &left.summary), value, right.map(|right| &right.summary))
         }
-        ret
     }
 }
 
-type AvlTree<'a, V: 'a> = BinaryTree<V, (i8, i8)>;
+impl <A> Heights for Augmented<A> {
+    fn heights(&self) -> &(i8, i8) {
+        &self.heights
+    }
+
+    fn heights_mut(&mut self) -> &mut (i8, i8) {
+        &mut self.heights
+    }
+}
+
+impl <V: Ord, M: Augment<V>> BinaryTree<V, M> {
+    fn leaf(value: V) -> Self {
+        BinaryTree {metadata: M::leaf(&value), size: 1, value: value, left: None, right: None}
+    }
+
+    // A node with the given children, its metadata and size worked out from theirs by
+    // fix_metadata. Every node that's built with children, rather than grown from a
+    // leaf, is made this way.
+    fn with_children(left: Option<Box<BinaryTree<V, M>>>, value: V, right: Option<Box<BinaryTree<V, M>>>) -> Self {
+        let metadata = M::leaf(&value);
+        let mut tree = BinaryTree {metadata: metadata, size: 1, value: value, left: left, right: right};
+        tree.fix_metadata();
+        tree
+    }
+
+    // Recomputes the metadata at our layer of the tree from our children's. The size
+    // of our subtree is recomputed from the children's sizes at the same time, so
+    // anything that keeps the metadata right keeps the sizes right too. This is the one
+    // place either of them is worked out for a node with children: rotations, inserts,
+    // removes and building trees from scratch all come through here, so there's no way
+    // to update one and forget the other.
+    fn fix_metadata(&mut self) {
+        self.metadata = M::combine(self.left.as_ref().map(|left| &left.metadata), &self.value,
+                                   self.right.as_ref().map(|right| &right.metadata));
+        self.size = 1 + size(&self.left) + size(&self.right);
+    }
+}
+
+// A leaf node for the value, in the spare allocation if there is one, or a new one if
+// not.
+fn new_leaf<V: Ord, M: Augment<V>>(value: V, spare: Option<Box<MaybeUninit<BinaryTree<V, M>>>>) -> Box<BinaryTree<V, M>> {
+    match spare {
+        Some(spare) => Box::write(spare, BinaryTree::leaf(value)),
+        None => Box::new(BinaryTree::leaf(value))
+    }
+}
 
-impl <'a> AvlTree<'a, i32> {
+impl <V: Ord, M: Augment<V>+Heights> BinaryTree<V, M> {
     #[allow(non_shorthand_field_patterns)]
     // As we recurse down, we build up an implicit insertion path on the stack.
     // If we do an insert succesfully (i.e.: it is not a duplicate value we are
     // attempting to insert), then we may or may not need to propagate up the
-    // stack how much the heights changed. The return value tells the caller
-    // how much the maximal height changed at our layer, so it can do the
-    // appropriate logic to decide what bookkeeping changes it needs to do.
-    fn insert(&mut self, new_value: i32) -> i8 {
-        let ret = match *self {
-            BinaryTree {value, left: None, right: None, ..} => {
-                if new_value > value {
-                    *self = BinaryTree {
-                        metadata: (0, 1),
-                        value: value,
-                        right: Some(Box::new(BinaryTree {
-                            metadata: (0, 0),
-                            value: new_value,
-                            left: None,
-                            right: None
-                        })),
-                        left: None
-                    }
-                } else if new_value == value {
-                   return 0 // we don't allow duplicates.
-                } else {
-                    *self = BinaryTree {
-                        metadata: (1, 0),
-                        value: value,
-                        left: Some(Box::new(BinaryTree {
-                            metadata: (0, 0),
-                            value: new_value,
-                            left: None,
-                            right: None
-                        })),
-                        right: None
+    // stack how much the heights changed. The outcome tells the caller whether
+    // anything was inserted at all, and if so whether the maximal height grew at
+    // our layer, so it can do the appropriate logic to decide what bookkeeping
+    // changes it needs to do. A duplicate leaves the shape of the whole path
+    // untouched, so it goes straight back up without any rebalancing.
+    //
+    // We don't allow duplicates, so when there's already an equal value, either it
+    // stays and the new one is handed back, or, when replacing, the new one takes
+    // its place and the old one is handed back instead.
+    //
+    // The only allocation an insert makes is the new leaf's node, so if the caller has
+    // already allocated one, it can pass that along as spare to be filled in, and
+    // nothing here allocates at all. Rebalancing only ever moves existing nodes around.
+    fn insert_with<P: BalancePolicy>(&mut self, new_value: V, replace: bool, spare: Option<Box<MaybeUninit<Self>>>) -> InsertOutcome<V> {
+        let outcome = match *self {
+            BinaryTree {ref mut value, ref left, ..} if new_value == *value => {
+                let value = if replace { mem::replace(value, new_value) } else { new_value };
+                return InsertOutcome::Duplicate {value, position: size(left)}
+            }
+            BinaryTree {ref mut metadata, ref value, left: Some(ref mut left), ..} if new_value < *value => {
+                match left.insert_with::<P>(new_value, replace, spare) {
+                    duplicate @ InsertOutcome::Duplicate {..} => return duplicate,
+                    InsertOutcome::Inserted {grew, position} => {
+                        let &mut (ref mut left_height, right_height) = metadata.heights_mut();
+                        if grew {
+                            *left_height += 1;
+                        }
+                        InsertOutcome::Inserted {grew: grew && *left_height == right_height + 1, position}
                     }
                 }
-                1
             }
-            BinaryTree {metadata: (ref mut left_height, right_height), ref mut value, left: Some(ref mut left ), ..} if new_value < *value => {
-                let incr = left.insert(new_value);
-                *left_height += incr;
-                assert!(incr < 2);
-                if *left_height == right_height + 1 { incr } else { 0 }
-            }
-            BinaryTree {metadata: (ref mut left_height, right_height), ref mut value, ref mut left, ..} if new_value < *value => {
+            BinaryTree {ref mut metadata, ref value, ref mut left, ..} if new_value < *value => {
+                let &mut (ref mut left_height, right_height) = metadata.heights_mut();
                 assert_eq!(0, *left_height);
 
-                *left = Some(Box::new(BinaryTree {value: new_value, metadata: (0, 0), left: None, right: None}));
+                *left = Some(new_leaf(new_value, spare));
                 *left_height += 1;
-                if *left_height == right_height + 1 { 1 } else { 0 }
+                InsertOutcome::Inserted {grew: *left_height == right_height + 1, position: 0}
             }
-            BinaryTree {metadata: (left_height, ref mut right_height), ref mut value, right: Some(ref mut right), ..} if new_value > *value => {
-                let incr = right.insert(new_value);
-                *right_height += incr;
-                assert!(incr < 2);
-                if *right_height == left_height + 1 { incr } else { 0 }
+            BinaryTree {ref mut metadata, ref value, ref left, right: Some(ref mut right), ..} if new_value > *value => {
+                // Everything on the left, and this node, come before anything on the right.
+                let before = size(left) + 1;
+                match right.insert_with::<P>(new_value, replace, spare) {
+                    InsertOutcome::Duplicate {value, position} => return InsertOutcome::Duplicate {value, position: before + position},
+                    InsertOutcome::Inserted {grew, position} => {
+                        let &mut (left_height, ref mut right_height) = metadata.heights_mut();
+                        if grew {
+                            *right_height += 1;
+                        }
+                        InsertOutcome::Inserted {grew: grew && *right_height == left_height + 1, position: before + position}
+                    }
+                }
             }
-            BinaryTree {metadata: (left_height, ref mut right_height), ref mut value, right: ref mut right, ..} if new_value > *value => {
+            BinaryTree {ref mut metadata, ref left, right: ref mut right, ..} => {
+                let &mut (left_height, ref mut right_height) = metadata.heights_mut();
                 assert_eq!(0, *right_height);
 
-                *right = Some(Box::new(BinaryTree {value: new_value, metadata: (0, 0), left: None, right: None}));
+                *right = Some(new_leaf(new_value, spare));
                 *right_height += 1;
-                if *right_height == left_height + 1 { 1 } else { 0 }
-            }
-            BinaryTree {ref mut value, ..} if *value == new_value => {
-                0 // this is a duplicate value, do nothing.
+                InsertOutcome::Inserted {grew: *right_height == left_height + 1, position: size(left) + 1}
             }
-            BinaryTree {..} => unreachable!()
         };
-        self.balance();
+        // The metadata has to be right before the policy sees it, and balancing fixes up
+        // whatever it moves, so the node is done once the policy is.
         self.fix_metadata();
-        ret
+        P::balance(self);
+        outcome
     }
 
-    // For each child we have, set the metadata at our layer of the tree to be
-    // 1 + max(left_height, right_height) where left_height and right_height are
-    // the values stored in that child's metadata. This is more verbose than it ideally
-    // would be because we have to match on every possible case.
-    fn fix_metadata(&mut self) {
-        match self {
-            &mut BinaryTree {
-                left: Some(box BinaryTree {metadata: (left_left, left_right), ..}),
-                right: Some(box BinaryTree {metadata: (right_left, right_right), ..}),
-                ..}
-            => {
-                self.metadata = (std::cmp::max(left_left, left_right) + 1, std::cmp::max(right_left, right_right) + 1);
-            }
-            &mut BinaryTree {left: None, right: Some(box BinaryTree {metadata: (right_left, right_right), ..}), ..}
-            => {
-                self.metadata = (0, std::cmp::max(right_left, right_right) + 1);
-            }
-            &mut BinaryTree {left: Some(box BinaryTree {metadata: (left_left, left_right), ..}), right: None, ..}
-            => {
-                self.metadata = (std::cmp::max(left_left, left_right) + 1, 0);
-            }
-            &mut BinaryTree {left: None, right: None, ..}
-            => {
-                self.metadata = (0, 0);
+    fn insert<P: BalancePolicy>(&mut self, new_value: V) -> InsertOutcome<V> {
+        self.check_ordering(&new_value);
+        self.insert_with::<P>(new_value, false, None)
+    }
+
+    // A broken Ord, one where a < b and b < a can both be true, say, or where comparing
+    // the same two values twice gives different answers, makes an insert put the value
+    // somewhere lookups will never find it, and nothing goes wrong until much later. So
+    // in debug builds, before inserting, we walk the path the insert is about to take
+    // and check the comparisons hang together along it: comparing each way round has
+    // to give opposite answers that agree with == and <, and the value has to still fall
+    // between every node we've gone left or right of so far. That's O(log n), and in
+    // release builds it compiles away to nothing.
+    #[cfg(debug_assertions)]
+    fn check_ordering(&self, new_value: &V) {
+        let (mut tree, mut lower, mut upper): (Option<&Self>, Option<&V>, Option<&V>) = (Some(self), None, None);
+        while let Some(node) = tree {
+            let ordering = new_value.cmp(&node.value);
+            assert!(node.value.cmp(new_value) == ordering.reverse() &&
+                        (*new_value == node.value) == (ordering == Ordering::Equal) &&
+                        (*new_value < node.value) == (ordering == Ordering::Less),
+                    "inconsistent Ord: comparing the same two values gave contradictory answers");
+            assert!(lower.is_none_or(|lower| *new_value > *lower) && upper.is_none_or(|upper| *new_value < *upper),
+                    "inconsistent Ord: a value went both left and right of the same node on its way down");
+            match ordering {
+                Ordering::Less => {
+                    upper = Some(&node.value);
+                    tree = node.left.as_deref();
+                }
+                Ordering::Greater => {
+                    lower = Some(&node.value);
+                    tree = node.right.as_deref();
+                }
+                Ordering::Equal => return
             }
         }
     }
 
-    // Rotations aren't inherently that complicated, but they sure are in Rust!
-    // (In other words, you're on your own here for now.)
+    #[cfg(not(debug_assertions))]
+    fn check_ordering(&self, _: &V) {}
+
+    // Rotations aren't inherently that complicated, but they sure are in Rust! The
+    // trouble is that whoever points at this subtree is pointing at self, so self has
+    // to end up holding the new root of the subtree. To rotate left, we detach our
+    // right child, hand its left subtree over to be our new right subtree, and then
+    // swap the contents of self with the detached child. Now self is the new root,
+    // and the old root is sitting in the box we detached, ready to become its left
+    // child. Rotating right is the mirror image.
     fn rotate_left(&mut self) {
-        let mut right: &mut Option<Box<AvlTree<i32>>> = &mut Some(Box::new(BinaryTree {metadata: (0,0), value: 0, right: None, left: None}));
-        mem::swap(right, &mut self.right);
-        mem::swap(&mut self.right, &mut right.as_mut().unwrap().left);
-        mem::swap(&mut **right.as_mut().unwrap().left.as_mut().unwrap(), self);
+        count_rotation();
+        let mut right = self.right.take().expect("rotating left requires a right child");
+        self.right = right.left.take();
+        self.fix_metadata();
+        mem::swap(self, &mut *right);
+        self.left = Some(right);
         self.fix_metadata();
-        right.as_mut().unwrap().left.as_mut().unwrap().fix_metadata();
-        right.as_mut().unwrap().fix_metadata();
-        mem::swap(self, right.as_mut().unwrap());
     }
 
     fn rotate_right(&mut self) {
-        let mut left: &mut Option<Box<AvlTree<i32>>> = &mut Some(Box::new(BinaryTree {metadata: (0,0), value: 0, left: None, right: None}));
-        mem::swap(left, &mut self.left);
-        mem::swap(&mut self.left, &mut left.as_mut().unwrap().right);
-        mem::swap(&mut **left.as_mut().unwrap().right.as_mut().unwrap(), self);
+        count_rotation();
+        let mut left = self.left.take().expect("rotating right requires a left child");
+        self.left = left.right.take();
+        self.fix_metadata();
+        mem::swap(self, &mut *left);
+        self.right = Some(left);
         self.fix_metadata();
-        left.as_mut().unwrap().right.as_mut().unwrap().fix_metadata();
-        left.as_mut().unwrap().fix_metadata();
-        mem::swap(self, left.as_mut().unwrap());
     }
 
     // As stated above, the definition of a balanced tree is one where the height
@@ -262,7 +515,7 @@ impl <'a> AvlTree<'a, i32> {
 
     // http://www.cise.ufl.edu/~nemo/cop3530/AVL-Tree-Rotations.pdf
     fn balance(&mut self) {
-        let difference: i8 = self.metadata.0 - self.metadata.1;
+        let difference: i8 = lean(&self.metadata);
 
         // if this fails, all hope is lost.
         assert!(difference <= 2);
@@ -273,7 +526,7 @@ impl <'a> AvlTree<'a, i32> {
                 Some(ref mut left @ box BinaryTree {left: None, right: Some(_), ..}) => {
                         left.rotate_left();
                 }
-                Some(ref mut left @ box BinaryTree {left: Some(_), right: Some(_), ..}) if left.metadata.0 - left.metadata.1 < 0 => {
+                Some(ref mut left @ box BinaryTree {left: Some(_), right: Some(_), ..}) if lean(&left.metadata) < 0 => {
                         left.rotate_left();
                 }
                 _ => ()
@@ -284,7 +537,7 @@ impl <'a> AvlTree<'a, i32> {
                 Some(ref mut right @ box BinaryTree {right: None, left: Some(_), ..}) => {
                         right.rotate_right();
                 }
-                Some(ref mut right @ box BinaryTree {right: Some(_), left: Some(_), ..}) if right.metadata.0 - right.metadata.1 > 0 => {
+                Some(ref mut right @ box BinaryTree {right: Some(_), left: Some(_), ..}) if lean(&right.metadata) > 0 => {
                         right.rotate_right();
                 }
                 _ => ()
@@ -294,4 +547,4347 @@ impl <'a> AvlTree<'a, i32> {
             unreachable!()
         }
     }
+
+    // The smallest and largest values in a subtree live at the ends of its left and
+    // right spines, so finding them is just a walk down one side.
+    fn leftmost(&self) -> &V {
+        match self.left {
+            Some(ref left) => left.leftmost(),
+            None => &self.value
+        }
+    }
+
+    fn rightmost(&self) -> &V {
+        match self.right {
+            Some(ref right) => right.rightmost(),
+            None => &self.value
+        }
+    }
+
+    fn leftmost_mut(&mut self) -> &mut V {
+        match self.left {
+            Some(ref mut left) => left.leftmost_mut(),
+            None => &mut self.value
+        }
+    }
+
+    fn rightmost_mut(&mut self) -> &mut V {
+        match self.right {
+            Some(ref mut right) => right.rightmost_mut(),
+            None => &mut self.value
+        }
+    }
+
+    // Removes the largest value from the subtree, handing back whatever is left of
+    // the subtree along with the value. Every node on the way down the right spine
+    // may have lost a level of height on its right, so each one gets rebalanced as
+    // the recursion unwinds, just like insert does.
+    fn pop_max(mut tree: Box<Self>) -> (Option<Box<Self>>, V) {
+        match tree.right.take() {
+            None => {
+                let tree = *tree;
+                (tree.left, tree.value)
+            }
+            Some(right) => {
+                let (right, max) = BinaryTree::pop_max(right);
+                tree.right = right;
+                tree.rebalance();
+                (Some(tree), max)
+            }
+        }
+    }
+
+    fn pop_min(mut tree: Box<Self>) -> (Option<Box<Self>>, V) {
+        match tree.left.take() {
+            None => {
+                let tree = *tree;
+                (tree.right, tree.value)
+            }
+            Some(left) => {
+                let (left, min) = BinaryTree::pop_min(left);
+                tree.left = left;
+                tree.rebalance();
+                (Some(tree), min)
+            }
+        }
+    }
+
+    // Removes the value from the subtree, if it's there, and hands it back. Once we
+    // find the node holding it, if the node has at most one child, that child simply
+    // takes its place. Otherwise, the smallest value in its right subtree is the next
+    // value after it in sorted order, so that value can take its place without
+    // disturbing the ordering of anything else. Like insert, each node on the way back
+    // up gets rebalanced, since one of its subtrees may have gotten shorter.
+    fn remove<Q: Ord+?Sized>(tree: &mut Option<Box<Self>>, value: &Q) -> Option<V> where V: Borrow<Q> {
+        BinaryTree::remove_by(tree, &|other: &V| value.cmp(other.borrow()))
+    }
+
+    // The same as remove, except that rather than a value to look for, it takes a
+    // function saying how whatever we're looking for compares to each value we pass.
+    fn remove_by<F: Fn(&V) -> Ordering>(tree: &mut Option<Box<Self>>, seek: &F) -> Option<V> {
+        let removed = match *tree {
+            None => return None,
+            Some(ref mut node) if seek(&node.value) == Ordering::Less => BinaryTree::remove_by(&mut node.left, seek),
+            Some(ref mut node) if seek(&node.value) == Ordering::Greater => BinaryTree::remove_by(&mut node.right, seek),
+            Some(_) => {
+                let node = *tree.take().unwrap();
+                *tree = match (node.left, node.right) {
+                    (None, right) => right,
+                    (left, None) => left,
+                    (left, Some(right)) => {
+                        let (right, min) = BinaryTree::pop_min(right);
+                        let mut replacement = Box::new(BinaryTree::with_children(left, min, right));
+                        replacement.rebalance();
+                        Some(replacement)
+                    }
+                };
+                return Some(node.value)
+            }
+        };
+
+        if removed.is_some() {
+            tree.as_mut().unwrap().rebalance();
+        }
+        removed
+    }
+
+    // Builds a balanced tree out of everything in left, then pivot, then everything in
+    // right. If the two sides are already within one level of each other, the pivot
+    // can just sit on top of them. Otherwise, we walk down the inner spine of the
+    // taller side until we find a subtree short enough to pair with the shorter
+    // side, join there, and rebalance each node on the way back up. Since we only
+    // ever walk one spine, this is O(difference in heights), which is O(log n).
+    fn join_with(left: Option<Box<Self>>, pivot: V, right: Option<Box<Self>>) -> Box<Self> {
+        let (left_height, right_height) = (height(&left), height(&right));
+
+        if left_height > right_height + 1 {
+            let mut left = left.unwrap();
+            let inner = left.right.take();
+            left.right = Some(BinaryTree::join_with(inner, pivot, right));
+            left.rebalance();
+            left
+        } else if right_height > left_height + 1 {
+            let mut right = right.unwrap();
+            let inner = right.left.take();
+            right.left = Some(BinaryTree::join_with(left, pivot, inner));
+            right.rebalance();
+            right
+        } else {
+            Box::new(BinaryTree::with_children(left, pivot, right))
+        }
+    }
+
+    // Joins two subtrees where everything in left is less than everything in right,
+    // borrowing the largest value of left as the pivot. See Tree::join.
+    fn join(left: Option<Box<Self>>, right: Option<Box<Self>>) -> Option<Box<Self>> {
+        match (left, right) {
+            (None, right) => right,
+            (left, None) => left,
+            (Some(left), right) => {
+                let (left, pivot) = BinaryTree::pop_max(left);
+                Some(BinaryTree::join_with(left, pivot, right))
+            }
+        }
+    }
+
+    // Splits a subtree in two: the values the predicate is true for, and the values
+    // it's false for, where the predicate has to be true for some prefix of the values
+    // in sorted order and false for the rest (like "less than 5"). At each node, we
+    // know which side the node goes on, and so which side its whole left or right
+    // subtree goes on. The other subtree straddles the split, so we split it
+    // recursively, and join the pieces that belong together, with the node as the
+    // pivot. That's one join per level, and it turns out the joins' costs add up to
+    // O(log n) overall, since each one is proportional to a difference in heights.
+    fn split_by<F: Fn(&V) -> bool>(tree: Option<Box<Self>>, goes_left: &F) -> (Option<Box<Self>>, Option<Box<Self>>) {
+        let tree = match tree {
+            Some(tree) => *tree,
+            None => return (None, None)
+        };
+
+        if goes_left(&tree.value) {
+            let (left, right) = BinaryTree::split_by(tree.right, goes_left);
+            (Some(BinaryTree::join_with(tree.left, tree.value, left)), right)
+        } else {
+            let (left, right) = BinaryTree::split_by(tree.left, goes_left);
+            (left, Some(BinaryTree::join_with(right, tree.value, tree.right)))
+        }
+    }
+
+    // After changing a child out from under a node, by something other than the
+    // bookkeeping insert does as it goes, the metadata needs to be recomputed before
+    // balance can trust it.
+    fn rebalance(&mut self) {
+        self.fix_metadata();
+        self.balance();
+        self.fix_metadata();
+    }
+}
+
+impl <V: Ord, M: Augment<V>+Heights> Rebalance for BinaryTree<V, M> {
+    fn restore_balance(&mut self) {
+        self.balance();
+    }
+
+    fn forget_heights(&mut self) {
+        *self.metadata.heights_mut() = (0, 0);
+    }
+}
+
+// Dropping a Box<AvlTree> drops its children, which drop their children, and so on,
+// using a stack frame per level of the tree. Instead, we pull the children off of
+// each node before it's dropped, so nothing is ever dropped with children still
+// attached, and the stack we use is a Vec on the heap.
+fn dismantle<V: Ord, M>(root: Option<Box<BinaryTree<V, M>>>) {
+    let mut to_drop: Vec<Box<BinaryTree<V, M>>> = root.into_iter().collect();
+    while let Some(mut tree) = to_drop.pop() {
+        to_drop.extend(tree.left.take());
+        to_drop.extend(tree.right.take());
+    }
+}
+
+// Takes a subtree apart in order, smallest value first, handing back the values as it
+// goes. This is an in-order traversal with an explicit stack: go as far left as we
+// can, and each time we can't go any further, the node on top of the stack is the
+// next smallest, and its right subtree is next up. Each node's children are detached
+// before it's dropped, just like in dismantle, and if the Drain is dropped before
+// it's done, whatever is left gets dismantled too.
+pub struct Drain<V: Ord> {
+    stack: Vec<Box<AvlTree<V>>>,
+    remaining: usize
+}
+
+impl <V: Ord> Drain<V> {
+    fn new(root: Option<Box<AvlTree<V>>>) -> Self {
+        let mut drain = Drain {stack: Vec::new(), remaining: size(&root)};
+        drain.push_left_spine(root);
+        drain
+    }
+
+    fn push_left_spine(&mut self, mut next: Option<Box<AvlTree<V>>>) {
+        while let Some(mut tree) = next {
+            next = tree.left.take();
+            self.stack.push(tree);
+        }
+    }
+}
+
+impl <V: Ord> Iterator for Drain<V> {
+    type Item = V;
+
+    fn next(&mut self) -> Option<V> {
+        let tree = *self.stack.pop()?;
+        self.push_left_spine(tree.right);
+        self.remaining -= 1;
+        Some(tree.value)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+impl <V: Ord> ExactSizeIterator for Drain<V> {}
+
+impl <V: Ord> Drop for Drain<V> {
+    fn drop(&mut self) {
+        while let Some(tree) = self.stack.pop() {
+            dismantle(Some(tree));
+        }
+    }
+}
+
+// Merges two strictly ascending sequences of values into one, keeping only the first
+// of any value that's in both, so the result is their union, still strictly ascending.
+fn union_sorted<V: Ord, I: Iterator<Item=V>>(existing: Vec<V>, items: I) -> Vec<V> {
+    let mut merged = Vec::with_capacity(existing.len() + items.size_hint().0);
+    let (mut existing, mut items) = (existing.into_iter().peekable(), items.peekable());
+    loop {
+        let order = match (existing.peek(), items.peek()) {
+            (Some(value), Some(item)) => value.cmp(item),
+            (Some(_), None) => Ordering::Less,
+            (None, Some(_)) => Ordering::Greater,
+            (None, None) => break
+        };
+        match order {
+            Ordering::Less => merged.extend(existing.next()),
+            Ordering::Greater => merged.extend(items.next()),
+            Ordering::Equal => {
+                items.next();
+                merged.extend(existing.next());
+            }
+        }
+    }
+    merged
+}
+
+// Folds over the subtree in order, stopping as soon as f breaks. It recurses rather
+// than keeping a stack, so it only goes as deep as the tree, and never allocates.
+fn try_fold_subtree<V: Ord, B, R, F: FnMut(B, &V) -> ControlFlow<R, B>>(tree: &Option<Box<AvlTree<V>>>, init: B, f: &mut F) -> ControlFlow<R, B> {
+    match *tree {
+        Some(ref node) => {
+            let acc = try_fold_subtree(&node.left, init, f)?;
+            let acc = f(acc, &node.value)?;
+            try_fold_subtree(&node.right, acc, f)
+        }
+        None => ControlFlow::Continue(init)
+    }
+}
+
+fn into_sorted<V: Ord>(root: Option<Box<AvlTree<V>>>) -> Vec<V> {
+    Drain::new(root).collect()
+}
+
+// Builds a perfectly balanced subtree out of the next n values of an ascending
+// iterator. Half the values (rounding down) go to the left, then the middle value is
+// the root, then the rest go to the right. Since the two halves never differ in size
+// by more than one, they never differ in height by more than one either, so there's
+// nothing to rotate, and every value is touched once.
+fn build_sorted<V: Ord, I: Iterator<Item=V>>(values: &mut I, n: usize) -> Option<Box<AvlTree<V>>> {
+    if n == 0 {
+        return None
+    }
+
+    let left = build_sorted(values, n / 2);
+    let value = values.next().expect("there should be n values left");
+    let right = build_sorted(values, n - n / 2 - 1);
+    Some(Box::new(BinaryTree::with_children(left, value, right)))
+}
+
+// Below this many values, building a subtree is quicker than handing it to another
+// thread.
+#[cfg(feature = "rayon")]
+const PARALLEL_THRESHOLD: usize = 1 << 14;
+
+// The same shape as build_sorted makes, but the two sides of each big enough subtree
+// get built at the same time, since neither depends on the other. Each side gets its
+// own part of the slice, and takes the values out of it as it goes, which is why
+// they're wrapped in Options: it's the one way to move values out of a slice we only
+// have a mutable borrow of. The alternative, splitting the Vec itself in two at every
+// level, would copy half the values into a new Vec every time.
+#[cfg(feature = "rayon")]
+fn build_sorted_par<V: Ord+Send>(values: &mut [Option<V>]) -> Option<Box<AvlTree<V>>> {
+    let n = values.len();
+    if n < PARALLEL_THRESHOLD {
+        return build_sorted(&mut values.iter_mut().map(|value| value.take().expect("every value is taken once")), n)
+    }
+
+    let (left_values, rest) = values.split_at_mut(n / 2);
+    let (middle, right_values) = rest.split_first_mut().expect("there is a middle value");
+    let value = middle.take().expect("every value is taken once");
+    let (left, right) = rayon::join(|| build_sorted_par(left_values), || build_sorted_par(right_values));
+    Some(Box::new(BinaryTree::with_children(left, value, right)))
+}
+
+// The same as build_sorted, except that the iterator is allowed to run out early. If it
+// does, some subtrees come out smaller than planned, so rather than just putting the
+// middle value on top of its two sides, we join them, which rebalances however far
+// apart their heights ended up. When the values do all turn up, the sides are never
+// more than one apart and the join is just the one node. In debug builds, this also
+// checks each value is greater than everything before it: whatever came right before
+// it is either the largest value to its left or, if it's the smallest of a right
+// subtree, the value just above that subtree.
+fn build_sorted_from<V: Ord, I: Iterator<Item=V>>(values: &mut I, n: usize) -> Option<Box<AvlTree<V>>> {
+    if n == 0 {
+        return None
+    }
+
+    let left = build_sorted_from(values, n / 2);
+    let value = match values.next() {
+        Some(value) => value,
+        None => return left
+    };
+    debug_assert!(left.as_ref().is_none_or(|left| *left.rightmost() < value), "values must be strictly ascending");
+    let right = build_sorted_from(values, n - n / 2 - 1);
+    debug_assert!(right.as_ref().is_none_or(|right| *right.leftmost() > value), "values must be strictly ascending");
+    Some(AvlTree::join_with(left, value, right))
+}
+
+// Iterates over the values of a tree in ascending order. This is the same in-order
+// traversal as into_sorted, except that it borrows instead of taking the tree apart,
+// and it's done lazily: the stack holds the nodes we've gone left past but haven't
+// visited yet, so the top of the stack is always the next smallest value. We also
+// know exactly how many values are left, which lets collecting into a Vec allocate
+// the right amount of space up front.
+pub struct SortedIterator<'a, V: 'a+Ord> {
+    stack: Vec<&'a AvlTree<V>>,
+    remaining: usize
+}
+
+impl <'a, V: 'a+Ord> SortedIterator<'a, V> {
+    fn push_left_spine(&mut self, mut next: &'a Option<Box<AvlTree<V>>>) {
+        while let Some(ref tree) = *next {
+            self.stack.push(tree);
+            next = &tree.left;
+        }
+    }
+}
+
+impl <'a, V: 'a+Ord> Iterator for SortedIterator<'a, V> {
+    type Item = &'a V;
+
+    fn next(&mut self) -> Option<&'a V> {
+        let tree = self.stack.pop()?;
+        self.push_left_spine(&tree.right);
+        self.remaining -= 1;
+        Some(&tree.value)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+impl <'a, V: 'a+Ord> ExactSizeIterator for SortedIterator<'a, V> {}
+
+// Each pair of neighbouring values, in ascending order, from one sorted traversal:
+// we just hang on to the last value it gave us, to pair up with the next one.
+pub struct Pairs<'a, V: 'a+Ord> {
+    values: SortedIterator<'a, V>,
+    previous: Option<&'a V>
+}
+
+impl <'a, V: 'a+Ord> Iterator for Pairs<'a, V> {
+    type Item = (&'a V, &'a V);
+
+    fn next(&mut self) -> Option<(&'a V, &'a V)> {
+        let previous = self.previous?;
+        let next = self.values.next()?;
+        self.previous = Some(next);
+        Some((previous, next))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let remaining = if self.previous.is_some() { self.values.len() } else { 0 };
+        (remaining, Some(remaining))
+    }
+}
+
+impl <'a, V: 'a+Ord> ExactSizeIterator for Pairs<'a, V> {}
+
+// The same again, but handing out mutable references. Borrowing a whole node mutably
+// would also borrow its left subtree, which we've already gone past and handed out
+// values from, so instead the stack holds each node's value and right subtree as two
+// separate borrows, which never overlap with anything else handed out. Changing a
+// value's place in the order through one of these would break the tree, so this is
+// only for the crate's own use, where the part a value is ordered by can't be
+// changed (a map's keys, say).
+pub(crate) struct SortedIteratorMut<'a, V: 'a+Ord> {
+    stack: Vec<(&'a mut V, &'a mut Option<Box<AvlTree<V>>>)>,
+    remaining: usize
+}
+
+impl <'a, V: 'a+Ord> SortedIteratorMut<'a, V> {
+    fn push_left_spine(&mut self, mut next: &'a mut Option<Box<AvlTree<V>>>) {
+        while let Some(ref mut tree) = *next {
+            let tree = &mut **tree;
+            self.stack.push((&mut tree.value, &mut tree.right));
+            next = &mut tree.left;
+        }
+    }
+}
+
+impl <'a, V: 'a+Ord> Iterator for SortedIteratorMut<'a, V> {
+    type Item = &'a mut V;
+
+    fn next(&mut self) -> Option<&'a mut V> {
+        let (value, right) = self.stack.pop()?;
+        self.push_left_spine(right);
+        self.remaining -= 1;
+        Some(value)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+impl <'a, V: 'a+Ord> ExactSizeIterator for SortedIteratorMut<'a, V> {}
+
+// The same in-order traversal as SortedIterator, except that every value comes with
+// how far down the tree its node is, with the root at depth 0. That's all anything
+// drawing the tree needs to know where to put each value. Depths aren't stored in
+// the nodes (every rotation would change them), but every node on the stack is one
+// deeper than whichever node we went left from to get to it, so we just remember the
+// depth alongside each node as we push it.
+pub struct DepthIterator<'a, V: 'a+Ord> {
+    stack: Vec<(&'a AvlTree<V>, usize)>,
+    remaining: usize
+}
+
+impl <'a, V: 'a+Ord> DepthIterator<'a, V> {
+    fn push_left_spine(&mut self, mut next: &'a Option<Box<AvlTree<V>>>, mut depth: usize) {
+        while let Some(ref tree) = *next {
+            self.stack.push((tree, depth));
+            next = &tree.left;
+            depth += 1;
+        }
+    }
+}
+
+impl <'a, V: 'a+Ord> Iterator for DepthIterator<'a, V> {
+    type Item = (&'a V, usize);
+
+    fn next(&mut self) -> Option<(&'a V, usize)> {
+        let (tree, depth) = self.stack.pop()?;
+        self.push_left_spine(&tree.right, depth + 1);
+        self.remaining -= 1;
+        Some((&tree.value, depth))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+impl <'a, V: 'a+Ord> ExactSizeIterator for DepthIterator<'a, V> {}
+
+// Iterates over every value in any of several trees, in ascending order, which is the
+// heart of a k-way merge, like merging the layers of an LSM tree. We keep an iterator
+// into each tree, and a heap holding the next value from each one, so the smallest
+// value left anywhere is always on top. Popping it and refilling the heap from the same
+// iterator is O(log k) for k trees. A value in several trees is only given once, from
+// the first of the trees it's in, and the same value from the others is skipped.
+pub struct MergeIterator<'a, V: 'a+Ord> {
+    iters: Vec<SortedIterator<'a, V>>,
+    heap: BinaryHeap<Reverse<(&'a V, usize)>>
+}
+
+pub fn merge_sorted<'a, V: Ord>(trees: &[&'a Tree<V>]) -> MergeIterator<'a, V> {
+    let mut iters: Vec<SortedIterator<'a, V>> = trees.iter().map(|tree| tree.iter_sorted()).collect();
+    let heap = iters.iter_mut().enumerate().filter_map(|(i, iter)| iter.next().map(|value| Reverse((value, i)))).collect();
+    MergeIterator {iters: iters, heap: heap}
+}
+
+impl <'a, V: 'a+Ord> MergeIterator<'a, V> {
+    fn refill(&mut self, i: usize) {
+        if let Some(value) = self.iters[i].next() {
+            self.heap.push(Reverse((value, i)));
+        }
+    }
+}
+
+impl <'a, V: 'a+Ord> Iterator for MergeIterator<'a, V> {
+    type Item = &'a V;
+
+    fn next(&mut self) -> Option<&'a V> {
+        let Reverse((value, i)) = self.heap.pop()?;
+        self.refill(i);
+        while let Some(&Reverse((next, j))) = self.heap.peek() {
+            if next != value {
+                break
+            }
+            self.heap.pop();
+            self.refill(j);
+        }
+        Some(value)
+    }
+}
+
+// Iterates over the values in exactly one of two trees, in ascending order. It walks
+// both trees in order at once, always stepping whichever is behind: a value that's
+// smaller than the other side's next value can't be in the other tree, so it's given,
+// and a value both sides have in common is skipped on both. Nothing gets collected,
+// it's just the two iterators, so it's O(n + m) over the whole walk.
+pub struct SymmetricDifference<'a, V: 'a+Ord> {
+    left: Peekable<SortedIterator<'a, V>>,
+    right: Peekable<SortedIterator<'a, V>>
+}
+
+impl <'a, V: 'a+Ord> Iterator for SymmetricDifference<'a, V> {
+    type Item = &'a V;
+
+    fn next(&mut self) -> Option<&'a V> {
+        loop {
+            let order = match (self.left.peek(), self.right.peek()) {
+                (Some(left), Some(right)) => left.cmp(right),
+                (Some(_), None) => Ordering::Less,
+                (None, Some(_)) => Ordering::Greater,
+                (None, None) => return None
+            };
+            match order {
+                Ordering::Less => return self.left.next(),
+                Ordering::Greater => return self.right.next(),
+                Ordering::Equal => {
+                    self.left.next();
+                    self.right.next();
+                }
+            }
+        }
+    }
+}
+
+// Whether a value is on the right side of the start or end of a range.
+pub(crate) fn after_start<V: Ord>(start: Bound<&V>, value: &V) -> bool {
+    match start {
+        Bound::Included(start) => value >= start,
+        Bound::Excluded(start) => value > start,
+        Bound::Unbounded => true
+    }
+}
+
+pub(crate) fn before_end<V: Ord>(end: Bound<&V>, value: &V) -> bool {
+    match end {
+        Bound::Included(end) => value <= end,
+        Bound::Excluded(end) => value < end,
+        Bound::Unbounded => true
+    }
+}
+
+// Iterates in ascending order over just the values within some bounds. It works like
+// SortedIterator, except that the stack starts out holding only the nodes on the path
+// down to the first value after the start of the range, which skips everything below
+// the range without visiting it, and it stops as soon as it passes the end.
+pub struct RangeIterator<'a, V: 'a+Ord, R: RangeBounds<V>> {
+    stack: Vec<&'a AvlTree<V>>,
+    bounds: R
+}
+
+impl <'a, V: 'a+Ord, R: RangeBounds<V>> Iterator for RangeIterator<'a, V, R> {
+    type Item = &'a V;
+
+    fn next(&mut self) -> Option<&'a V> {
+        let tree = self.stack.pop()?;
+        if !before_end(self.bounds.end_bound(), &tree.value) {
+            self.stack.clear();
+            return None
+        }
+
+        let mut next = &tree.right;
+        while let Some(ref right) = *next {
+            self.stack.push(right);
+            next = &right.left;
+        }
+        Some(&tree.value)
+    }
+}
+
+// A position in the tree, which can be moved forwards and backwards one value at a
+// time. A cursor sits in between two neighbouring values (or before the first, or after
+// the last), so there's always a next and a previous value to peek at, unless it's at
+// one of the ends of the tree.
+//
+// The cursor holds the path from the root down to the node with the next value in it,
+// so moving never has to go back to the root: the next value after a node is either
+// the smallest value in its right subtree, or else the first ancestor we reach from
+// its left subtree when climbing back up the path. The previous value is the mirror
+// image. Each move is O(log n) at worst, but a run of moves visits every node on the
+// way at most twice, just like iterating does. An empty path means the cursor is past
+// the end of the tree.
+pub struct Cursor<'a, V: 'a+Ord> {
+    root: &'a Option<Box<AvlTree<V>>>,
+    path: Vec<&'a AvlTree<V>>
+}
+
+fn is_left_child<V: Ord>(parent: &AvlTree<V>, child: &AvlTree<V>) -> bool {
+    parent.left.as_deref().is_some_and(|left| ptr::eq(left, child))
+}
+
+impl <'a, V: 'a+Ord> Cursor<'a, V> {
+    fn push_left_spine(&mut self, mut next: &'a Option<Box<AvlTree<V>>>) {
+        while let Some(ref tree) = *next {
+            self.path.push(tree);
+            next = &tree.left;
+        }
+    }
+
+    fn push_right_spine(&mut self, mut next: &'a Option<Box<AvlTree<V>>>) {
+        while let Some(ref tree) = *next {
+            self.path.push(tree);
+            next = &tree.right;
+        }
+    }
+
+    pub fn peek_next(&self) -> Option<&'a V> {
+        self.path.last().map(|tree| &tree.value)
+    }
+
+    pub fn peek_prev(&self) -> Option<&'a V> {
+        let tree = match self.path.last() {
+            Some(tree) => tree,
+            None => return self.root.as_ref().map(|root| root.rightmost())
+        };
+        match tree.left {
+            Some(ref left) => Some(left.rightmost()),
+            None => self.path.windows(2).rev().find(|pair| !is_left_child(pair[0], pair[1])).map(|pair| &pair[0].value)
+        }
+    }
+
+    // Moves the cursor forward past the next value, and returns it. At the end of the
+    // tree, this returns None and the cursor stays where it is.
+    pub fn move_next(&mut self) -> Option<&'a V> {
+        let tree = *self.path.last()?;
+        match tree.right {
+            Some(_) => self.push_left_spine(&tree.right),
+            None => {
+                // Climb until we come up out of a left subtree. If we never do, we've
+                // just passed the largest value, and the path ends up empty.
+                while let Some(child) = self.path.pop() {
+                    match self.path.last() {
+                        Some(parent) if is_left_child(parent, child) => break,
+                        _ => ()
+                    }
+                }
+            }
+        }
+        Some(&tree.value)
+    }
+
+    // Moves the cursor back past the previous value, and returns it. At the start of
+    // the tree, this returns None and the cursor stays where it is.
+    pub fn move_prev(&mut self) -> Option<&'a V> {
+        let prev = self.peek_prev()?;
+        match self.path.last().cloned() {
+            None => self.push_right_spine(self.root),
+            Some(tree) => match tree.left {
+                Some(ref left) => {
+                    self.path.push(left);
+                    self.push_right_spine(&left.right);
+                }
+                None => {
+                    // Climb until we come up out of a right subtree, which peek_prev has
+                    // already told us we will.
+                    while let Some(child) = self.path.pop() {
+                        if !is_left_child(self.path.last().expect("there is a previous value"), child) {
+                            break
+                        }
+                    }
+                }
+            }
+        }
+        Some(prev)
+    }
+}
+
+// A read-only view of one node, for anything that wants to walk the tree's actual
+// shape, like something drawing it or measuring how it's laid out. It borrows the
+// node itself, so nothing gets copied, and there's no way to change anything through
+// it. The nodes' fields stay private, so the tree is free to change how they're laid
+// out, and this is what stays the same.
+pub struct NodeRef<'a, V: 'a+Ord> {
+    node: &'a AvlTree<V>
+}
+
+// Derived Clone and Copy would want V to be Clone and Copy, but copying a NodeRef
+// just copies the reference.
+impl <'a, V: 'a+Ord> Clone for NodeRef<'a, V> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl <'a, V: 'a+Ord> Copy for NodeRef<'a, V> {}
+
+impl <'a, V: 'a+Ord> NodeRef<'a, V> {
+    pub fn value(&self) -> &'a V {
+        &self.node.value
+    }
+
+    pub fn left(&self) -> Option<NodeRef<'a, V>> {
+        self.node.left.as_ref().map(|left| NodeRef {node: &**left})
+    }
+
+    pub fn right(&self) -> Option<NodeRef<'a, V>> {
+        self.node.right.as_ref().map(|right| NodeRef {node: &**right})
+    }
+
+    // The heights of the left and right subtrees, as the node keeps them.
+    pub fn metadata(&self) -> (i8, i8) {
+        self.node.metadata
+    }
+
+    // How many values are in the subtree this node is the root of.
+    pub fn size(&self) -> usize {
+        self.node.size
+    }
+}
+
+// Which end of the tree insert_capped makes room at.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Evict {
+    Smallest,
+    Largest
+}
+
+// A summary of a tree's shape, for checking on its health at a glance, or comparing
+// what different ways of building a tree end up with. Depths count the root as 0, and
+// the height counts levels, so a tree of one value has height 1. A tree with no values
+// has no leaves, and its average leaf depth is 0.
+#[derive(Debug, PartialEq)]
+pub struct TreeStats<'a, V: 'a> {
+    pub len: usize,
+    pub height: usize,
+    pub min: Option<&'a V>,
+    pub max: Option<&'a V>,
+    pub max_imbalance: i8,
+    pub leaves: usize,
+    pub average_leaf_depth: f64
+}
+
+impl <V: Ord+Debug, P: BalancePolicy> Debug for Tree<V, P> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.root {
+            Some(ref root) => root.fmt(f),
+            None => writeln!(f, "(empty)")
+        }
+    }
+}
+
+impl <V: Ord, P: BalancePolicy> Drop for Tree<V, P> {
+    fn drop(&mut self) {
+        dismantle(self.root.take());
+    }
+}
+
+impl <V: Ord> Default for Tree<V> {
+    fn default() -> Self {
+        Tree::new()
+    }
+}
+
+// Collecting into a tree doesn't insert the values one at a time, rebalancing as it
+// goes. It collects them into a Vec, sorts that and drops the duplicates, and then
+// builds the tree in one go with from_sorted. That's still O(n log n) comparisons for
+// the sort, but no rotations at all, and each node is written once. The sort is
+// stable and dedup keeps the first of each run of equal values, so of several equal
+// values the first one seen wins, just as it would inserting them one by one.
+impl <V: Ord> FromIterator<V> for Tree<V> {
+    fn from_iter<I: IntoIterator<Item=V>>(values: I) -> Self {
+        let mut values: Vec<V> = values.into_iter().collect();
+        values.sort();
+        values.dedup();
+        Tree::from_sorted(values)
+    }
+}
+
+// Two trees are equal when they hold the same values, however differently they're
+// shaped, and they're ordered by comparing their values in ascending order, so that
+// a tree that's a prefix of another comes first, just like comparing slices. That
+// way trees can themselves go in a BTreeSet, or a Tree. Trees with different
+// lengths can't be equal, so that's checked first, before walking either of them.
+impl <V: Ord> PartialEq for Tree<V> {
+    fn eq(&self, other: &Tree<V>) -> bool {
+        self.len == other.len && self.iter_sorted().eq(other.iter_sorted())
+    }
+}
+
+impl <V: Ord> Eq for Tree<V> {}
+
+impl <V: Ord> PartialOrd for Tree<V> {
+    fn partial_cmp(&self, other: &Tree<V>) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl <V: Ord> Ord for Tree<V> {
+    fn cmp(&self, other: &Tree<V>) -> Ordering {
+        self.iter_sorted().cmp(other.iter_sorted())
+    }
+}
+
+impl <V: Ord> Tree<V> {
+    // An empty tree. Nodes are allocated one at a time as values go in, so unlike Vec
+    // there's no capacity to reserve up front, and no with_capacity.
+    pub fn new() -> Self {
+        Tree::with_policy()
+    }
+}
+
+// A tree that keeps its largest values first doesn't need a comparator of its own:
+// wrapping every value in Reverse flips its Ord, and the tree just follows it. So
+// Tree<Reverse<V>> iterates from largest to smallest, first and pop_first are the
+// largest, and ranges run downwards, like tree.range(Reverse(7)..=Reverse(3)) for 7
+// down to 3. Nothing in the tree assumes anything about the order beyond it being
+// total, so all of it works the same either way around.
+impl <V: Ord> Tree<Reverse<V>> {
+    // An empty tree of Reversed values, which is just Tree::new with the type spelled
+    // out, for when nothing else pins it down.
+    pub fn reversed() -> Self {
+        Tree::new()
+    }
+}
+
+impl <V: Ord, P: BalancePolicy> Tree<V, P> {
+    // An empty tree that follows the given balance policy, like
+    // Tree::<i32, NoRebalance>::with_policy(). Tree::new is the same with Avl.
+    pub fn with_policy() -> Self {
+        Tree {root: None, len: 0, #[cfg(feature = "rotation-count")] rotations: 0, policy: PhantomData}
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    // How many levels the tree has, counting the root, so an empty tree has height 0.
+    pub fn height(&self) -> usize {
+        if P::REBALANCES {
+            return height(&self.root) as usize
+        }
+        // Trees that don't rebalance don't keep their heights, so we have to go and
+        // look, keeping a stack of our own since the tree could be as deep as it's long.
+        let mut tallest = 0;
+        let mut stack: Vec<(&AvlTree<V>, usize)> = self.root.iter().map(|root| (&**root, 1)).collect();
+        while let Some((tree, depth)) = stack.pop() {
+            tallest = max(tallest, depth);
+            for child in tree.left.iter().chain(tree.right.iter()) {
+                stack.push((child, depth + 1));
+            }
+        }
+        tallest
+    }
+
+    pub fn iter_sorted<'a>(&'a self) -> SortedIterator<'a, V> {
+        let mut iter = SortedIterator {stack: Vec::new(), remaining: self.len};
+        iter.push_left_spine(&self.root);
+        iter
+    }
+
+    // The same as iter_sorted, under the name BTreeSet uses. With this, a Tree answers
+    // to all of BTreeSet's everyday read-only methods, contains, iter, range, first,
+    // last, len and is_empty, so code that only reads a set can switch from one to the
+    // other by changing the type. The differences to watch for are that the iterators
+    // only go forwards, so there's no rev(), that range has to be bounded by values
+    // rather than any borrowed form of them, and that a range whose start is after its
+    // end is just empty, where BTreeSet panics. Equality is the same for both: two sets
+    // are equal when they hold the same values, whatever their shapes.
+    pub fn iter<'a>(&'a self) -> SortedIterator<'a, V> {
+        self.iter_sorted()
+    }
+
+    // Like BTreeSet, lookups can go by any borrowed form of the values, as long as it's
+    // ordered the same way, so a tree of Strings can be searched with a &str, without
+    // allocating a String to search with.
+    pub fn contains<Q: Ord+?Sized>(&self, value: &Q) -> bool where V: Borrow<Q> {
+        self.get(value).is_some()
+    }
+
+    // The value in the tree equal to this one, which can matter when equal values
+    // aren't identical.
+    pub fn get<Q: Ord+?Sized>(&self, value: &Q) -> Option<&V> where V: Borrow<Q> {
+        self.get_by(|other| value.cmp(other.borrow()))
+    }
+
+    pub(crate) fn get_by<F: Fn(&V) -> Ordering>(&self, seek: F) -> Option<&V> {
+        let mut tree = &self.root;
+        while let Some(ref node) = *tree {
+            match seek(&node.value) {
+                Ordering::Less => tree = &node.left,
+                Ordering::Greater => tree = &node.right,
+                Ordering::Equal => return Some(&node.value)
+            }
+        }
+        None
+    }
+
+    // Returns whether the value was actually added, i.e. false if it was already in
+    // the tree.
+    pub fn insert(&mut self, value: V) -> bool {
+        let added = self.insert_unchecked(value);
+        self.changed("insert");
+        added
+    }
+
+    // Everything insert does but the call to changed every public operation ends with,
+    // for operations that insert a whole batch, so they can call it once at the end.
+    // Otherwise a debug build would check the whole tree after every value in the
+    // batch, making the batch O(k n).
+    fn insert_unchecked(&mut self, value: V) -> bool {
+        let added = match self.root {
+            Some(ref mut root) => match root.insert::<P>(value) {
+                InsertOutcome::Duplicate {..} => false,
+                InsertOutcome::Inserted {..} => true
+            },
+            None => {
+                self.root = Some(Box::new(AvlTree::leaf(value)));
+                true
+            }
+        };
+        if added {
+            self.len += 1;
+        }
+        added
+    }
+}
+
+impl <V: Ord> Tree<V> {
+    // How many rotations this tree has done since it was made, counting those done by
+    // the trees joined together to make it. Only there with the rotation-count feature.
+    #[cfg(feature = "rotation-count")]
+    pub fn rotation_count(&self) -> u64 {
+        self.rotations
+    }
+
+    // Roughly how many bytes the tree takes up: the Tree itself, plus one node per
+    // value, each in its own allocation. A Box has no header of its own, and the
+    // pointers to the children are counted as part of their parents' nodes. This is
+    // only an estimate, since it leaves out whatever the allocator adds to each
+    // allocation for its own bookkeeping, and anything the values own on the heap
+    // themselves. It goes by len() rather than walking the tree, so it's O(1).
+    pub fn memory_usage(&self) -> usize {
+        mem::size_of::<Tree<V>>() + self.len * mem::size_of::<AvlTree<V>>()
+    }
+
+    // Empties the tree, leaving it just as Tree::new() would, except for its rotation
+    // count, which covers the tree's whole lifetime, so it carries on from where it was.
+    pub fn clear(&mut self) {
+        dismantle(self.root.take());
+        self.len = 0;
+        self.changed("clear");
+    }
+
+    // Whether the two trees are the same node for node: the same values, in the same
+    // places, with the same metadata. == only asks whether they hold the same values,
+    // which two trees built in different orders usually do while being shaped quite
+    // differently, so this is for when the shape itself is what matters, like checking
+    // two ways of building a tree come out identical.
+    pub fn structurally_eq(&self, other: &Tree<V>) -> bool {
+        self.len == other.len && same_structure(&self.root, &other.root)
+    }
+
+    // The smallest and largest values, found by walking down the left and right
+    // spines, so O(log n) rather than a traversal.
+    pub fn first(&self) -> Option<&V> {
+        self.root.as_ref().map(|root| root.leftmost())
+    }
+
+    pub fn last(&self) -> Option<&V> {
+        self.root.as_ref().map(|root| root.rightmost())
+    }
+
+    // Like get_mut_by, these are only for the crate's own use, where the part of the
+    // value it's ordered by can't be changed.
+    pub(crate) fn first_mut(&mut self) -> Option<&mut V> {
+        self.root.as_mut().map(|root| root.leftmost_mut())
+    }
+
+    pub(crate) fn last_mut(&mut self) -> Option<&mut V> {
+        self.root.as_mut().map(|root| root.rightmost_mut())
+    }
+
+    // Removes and hands back the smallest value, rebalancing each node on the way back
+    // up the left spine, so it's O(log n), and the tree can be used as a priority queue.
+    pub fn pop_first(&mut self) -> Option<V> {
+        let (rest, first) = AvlTree::pop_min(self.root.take()?);
+        self.root = rest;
+        self.len -= 1;
+        self.changed("pop_first");
+        Some(first)
+    }
+
+    pub fn pop_last(&mut self) -> Option<V> {
+        let (rest, last) = AvlTree::pop_max(self.root.take()?);
+        self.root = rest;
+        self.len -= 1;
+        self.changed("pop_last");
+        Some(last)
+    }
+
+    // How much taller the root's left subtree is than its right, which is the same
+    // quantity balance looks at to decide whether to rotate. In a correct AVL tree this
+    // is always -1, 0 or 1.
+    pub fn balance_factor(&self) -> i8 {
+        self.root.as_ref().map_or(0, |root| root.metadata.0 - root.metadata.1)
+    }
+
+    // The most unbalanced any node in the tree is, i.e. the largest absolute balance
+    // factor found anywhere. This should never be more than 1, so it makes a cheap
+    // health check after a batch of operations, if not quite as thorough a one as
+    // check_invariants. It's O(n), since it looks at every node.
+    pub fn max_imbalance(&self) -> i8 {
+        let mut max_imbalance = 0;
+        let mut to_visit: Vec<&AvlTree<V>> = self.root.iter().map(|root| &**root).collect();
+        while let Some(tree) = to_visit.pop() {
+            max_imbalance = max(max_imbalance, (tree.metadata.0 - tree.metadata.1).abs());
+            to_visit.extend(tree.left.iter().chain(tree.right.iter()).map(|child| &**child));
+        }
+        max_imbalance
+    }
+
+    // Works out everything in TreeStats. The height, length and ends of the tree are all
+    // known without a traversal, and the rest comes from one walk over every node.
+    pub fn stats<'a>(&'a self) -> TreeStats<'a, V> {
+        let (mut max_imbalance, mut leaves, mut leaf_depths) = (0, 0, 0);
+        let mut to_visit: Vec<(&AvlTree<V>, usize)> = self.root.iter().map(|root| (&**root, 0)).collect();
+        while let Some((tree, depth)) = to_visit.pop() {
+            max_imbalance = max(max_imbalance, (tree.metadata.0 - tree.metadata.1).abs());
+            if tree.left.is_none() && tree.right.is_none() {
+                leaves += 1;
+                leaf_depths += depth;
+            }
+            to_visit.extend(tree.left.iter().chain(tree.right.iter()).map(|child| (&**child, depth + 1)));
+        }
+
+        TreeStats {
+            len: self.len,
+            height: height(&self.root) as usize,
+            min: self.first(),
+            max: self.last(),
+            max_imbalance: max_imbalance,
+            leaves: leaves,
+            average_leaf_depth: if leaves == 0 { 0.0 } else { leaf_depths as f64 / leaves as f64 }
+        }
+    }
+
+    // The value at the given position in sorted order, counting from zero. Since every
+    // node knows the size of its left subtree, it knows how many values come before
+    // it, so we can steer toward the index at each step instead of iterating, which
+    // makes this O(log n).
+    pub fn nth(&self, index: usize) -> Option<&V> {
+        let mut index = index;
+        let mut tree = &self.root;
+        while let Some(ref node) = *tree {
+            let before = size(&node.left);
+            if index < before {
+                tree = &node.left;
+            } else if index > before {
+                index -= before + 1;
+                tree = &node.right;
+            } else {
+                return Some(&node.value)
+            }
+        }
+        None
+    }
+
+    // The value at the given quantile, from 0.0 for the smallest to 1.0 for the
+    // largest, so 0.5 is the median. The values needn't be numbers, so there's no
+    // averaging two of them when the quantile falls in between: this picks the one at
+    // position q * (len - 1), rounded down. That makes the median of an even number of
+    // values the lower of the middle two. Anything outside 0.0 to 1.0 is clamped into
+    // it, and NaN counts as 0.0. It's one nth, so O(log n).
+    pub fn quantile(&self, q: f64) -> Option<&V> {
+        if self.len == 0 {
+            return None
+        }
+        let q = if q.is_nan() { 0.0 } else { q.clamp(0.0, 1.0) };
+        // Truncating rounds down, since q is never negative.
+        self.nth((q * (self.len - 1) as f64) as usize)
+    }
+
+    // How many values in the tree are less than the given value, which is also the
+    // position it has (or would have) in sorted order. Going right past a node means
+    // the node and its whole left subtree are less than the value, so we count them
+    // as we go.
+    pub fn rank(&self, value: &V) -> usize {
+        self.count_before(value, false)
+    }
+
+    fn count_before(&self, value: &V, inclusive: bool) -> usize {
+        let mut count = 0;
+        let mut tree = &self.root;
+        while let Some(ref node) = *tree {
+            if node.value < *value || (inclusive && node.value == *value) {
+                count += size(&node.left) + 1;
+                tree = &node.right;
+            } else {
+                tree = &node.left;
+            }
+        }
+        count
+    }
+
+    // How many values are strictly less than this one, which is just its rank, and the
+    // other three ways of counting from one side or the other. Each is a single O(log n)
+    // descent, and for any value, less than and at least always add up to the length,
+    // as do at most and greater than.
+    pub fn count_less_than(&self, value: &V) -> usize {
+        self.count_before(value, false)
+    }
+
+    pub fn count_at_most(&self, value: &V) -> usize {
+        self.count_before(value, true)
+    }
+
+    pub fn count_greater_than(&self, value: &V) -> usize {
+        self.len - self.count_before(value, true)
+    }
+
+    pub fn count_at_least(&self, value: &V) -> usize {
+        self.len - self.count_before(value, false)
+    }
+
+    // Where the value would go in sorted order, named after Python's bisect module:
+    // bisect_left is the position before an equal value that's already there, and
+    // bisect_right the position after it. For a value that isn't in the tree they're
+    // the same. bisect_left is just rank, and bisect_right is rank plus one for a value
+    // that's present, and both are O(log n).
+    pub fn bisect_left(&self, value: &V) -> usize {
+        self.count_before(value, false)
+    }
+
+    pub fn bisect_right(&self, value: &V) -> usize {
+        self.count_before(value, true)
+    }
+
+    // Counts the values within some bounds without visiting them. The number of values
+    // in a range is the number before its end minus the number before its start, and
+    // each of those is one O(log n) descent, no matter how big the range is.
+    pub fn count_range<R: RangeBounds<V>>(&self, bounds: R) -> usize {
+        let before_start = match bounds.start_bound() {
+            Bound::Included(start) => self.count_before(start, false),
+            Bound::Excluded(start) => self.count_before(start, true),
+            Bound::Unbounded => 0
+        };
+        let through_end = match bounds.end_bound() {
+            Bound::Included(end) => self.count_before(end, true),
+            Bound::Excluded(end) => self.count_before(end, false),
+            Bound::Unbounded => self.len
+        };
+        through_end.saturating_sub(before_start)
+    }
+
+    // Whether anything at all is between lo and hi, inclusive. At each node, if its
+    // value is below lo, so is everything to its left, and if it's above hi, so is
+    // everything to its right, so we only ever go one way, and stop at the first value
+    // that's in range. That's a single descent with no stack, unlike starting up a
+    // range iterator. If lo is greater than hi, nothing is between them.
+    pub fn any_in_range(&self, lo: &V, hi: &V) -> bool {
+        if lo > hi {
+            return false
+        }
+
+        let mut tree = &self.root;
+        while let Some(ref node) = *tree {
+            if node.value < *lo {
+                tree = &node.right;
+            } else if node.value > *hi {
+                tree = &node.left;
+            } else {
+                return true
+            }
+        }
+        false
+    }
+
+    // The smallest and largest values within some bounds, or None if there aren't any,
+    // without iterating over the range. It's one descent to find the first value after
+    // the start, which is the same walk range starts with, and one to find the last
+    // value before the end, its mirror image. If the first is past the end, nothing
+    // is in between.
+    pub fn range_bounds<R: RangeBounds<V>>(&self, bounds: R) -> Option<(&V, &V)> {
+        let (mut tree, mut first) = (&self.root, None);
+        while let Some(ref node) = *tree {
+            if after_start(bounds.start_bound(), &node.value) {
+                first = Some(&node.value);
+                tree = &node.left;
+            } else {
+                tree = &node.right;
+            }
+        }
+        let first = first.filter(|first| before_end(bounds.end_bound(), first))?;
+
+        let (mut tree, mut last) = (&self.root, first);
+        while let Some(ref node) = *tree {
+            if before_end(bounds.end_bound(), &node.value) {
+                last = &node.value;
+                tree = &node.right;
+            } else {
+                tree = &node.left;
+            }
+        }
+        Some((first, last))
+    }
+
+    // The values within some bounds, in ascending order. To find where the range
+    // starts, we descend as if searching for its start, and every node we go left past
+    // is in the range (as far as the start is concerned), so it goes on the stack to
+    // be visited later, just like SortedIterator's left spine.
+    pub fn range<'a, R: RangeBounds<V>>(&'a self, bounds: R) -> RangeIterator<'a, V, R> {
+        let mut stack = Vec::new();
+        let mut tree = &self.root;
+        while let Some(ref node) = *tree {
+            if after_start(bounds.start_bound(), &node.value) {
+                stack.push(&**node);
+                tree = &node.left;
+            } else {
+                tree = &node.right;
+            }
+        }
+        RangeIterator {stack: stack, bounds: bounds}
+    }
+
+    // The first value within the bounds that the predicate is true for. The range
+    // iterator is lazy, so this is its descent to the start of the range, and then only
+    // as many steps as it takes to find a match: nothing after it is visited, and
+    // nothing before the range at all.
+    pub fn find_in_range<R: RangeBounds<V>, F: FnMut(&V) -> bool>(&self, bounds: R, mut pred: F) -> Option<&V> {
+        self.range(bounds).find(|value| pred(value))
+    }
+
+    // Iterates in order from the first value that isn't less than the one given, to
+    // the end. This is the same descent range starts with: every node we go left past
+    // comes at or after the value, so it goes on the stack to be visited later, and
+    // everything we go right past comes before it, so we count it to know how many are
+    // left. That makes starting anywhere O(log n), and since there's no end bound to
+    // check, it's a plain SortedIterator, which knows its exact length.
+    pub fn iter_from<'a>(&'a self, value: &V) -> SortedIterator<'a, V> {
+        let (mut stack, mut skipped) = (Vec::new(), 0);
+        let mut tree = &self.root;
+        while let Some(ref node) = *tree {
+            if node.value >= *value {
+                stack.push(&**node);
+                tree = &node.left;
+            } else {
+                skipped += size(&node.left) + 1;
+                tree = &node.right;
+            }
+        }
+        SortedIterator {stack: stack, remaining: self.len - skipped}
+    }
+
+    // A cursor sitting just before the first value that isn't less than the one given,
+    // so if it's in the tree it's the next value, and otherwise the cursor is in between
+    // the values either side of where it would be.
+    pub fn cursor_at<'a>(&'a self, value: &V) -> Cursor<'a, V> {
+        self.cursor_before(|other| value <= other)
+    }
+
+    // The same cursors as in C++'s lower_bound and upper_bound. The lower bound is just
+    // before the first value that isn't less than the one given, exactly like
+    // cursor_at, and the upper bound is just before the first value that's greater than
+    // it. Both are at the end of the tree if there isn't any such value, and before the
+    // first value if every value is.
+    pub fn lower_bound<'a>(&'a self, value: &V) -> Cursor<'a, V> {
+        self.cursor_before(|other| value <= other)
+    }
+
+    pub fn upper_bound<'a>(&'a self, value: &V) -> Cursor<'a, V> {
+        self.cursor_before(|other| value < other)
+    }
+
+    // A cursor just before the first value the predicate is true for, given that it's
+    // false for every value before that and true for every value after. We descend
+    // keeping the whole path, going left whenever it's true, since the value we're after
+    // is either that one or somewhere to its left, and then cut the path off at the
+    // last node we went left from, since that's the node with the next value.
+    fn cursor_before<'a, F: Fn(&V) -> bool>(&'a self, at_or_after: F) -> Cursor<'a, V> {
+        let mut path = Vec::new();
+        let mut next_at = 0;
+        let mut tree = &self.root;
+        while let Some(ref node) = *tree {
+            path.push(&**node);
+            if at_or_after(&node.value) {
+                next_at = path.len();
+                tree = &node.left;
+            } else {
+                tree = &node.right;
+            }
+        }
+        path.truncate(next_at);
+        Cursor {root: &self.root, path: path}
+    }
+
+    // The root node, to walk the tree's structure from, or None if it's empty.
+    pub fn root<'a>(&'a self) -> Option<NodeRef<'a, V>> {
+        self.root.as_ref().map(|root| NodeRef {node: &**root})
+    }
+
+    // Iterates in order over the subtree under the node holding this value, or gives
+    // None if the value isn't in the tree. That's every value between the nearest nodes
+    // we went left and right of on the way down, so it's like a range whose bounds come
+    // from the shape of the tree rather than being given. Finding the node is O(log n),
+    // and from there it's the same as iterating over a whole tree.
+    pub fn subtree_iter<'a, Q: Ord+?Sized>(&'a self, value: &Q) -> Option<SortedIterator<'a, V>> where V: Borrow<Q> {
+        let mut tree = &self.root;
+        while let Some(ref node) = *tree {
+            match value.cmp(node.value.borrow()) {
+                Ordering::Less => tree = &node.left,
+                Ordering::Greater => tree = &node.right,
+                Ordering::Equal => {
+                    let mut iter = SortedIterator {stack: vec![&**node], remaining: node.size};
+                    iter.push_left_spine(&node.left);
+                    return Some(iter)
+                }
+            }
+        }
+        None
+    }
+
+    // The same as iter_sorted().fold(init, f), but walking the tree by recursion, which
+    // an AVL tree's height keeps shallow, rather than through an iterator with a stack
+    // to allocate. For simple reductions like sums and counts, that's less overhead.
+    pub fn fold_in_order<B, F: FnMut(B, &V) -> B>(&self, init: B, mut f: F) -> B {
+        match self.try_fold_in_order(init, |acc, value| ControlFlow::<Infallible, B>::Continue(f(acc, value))) {
+            ControlFlow::Continue(acc) => acc,
+            ControlFlow::Break(never) => match never {}
+        }
+    }
+
+    // Like fold_in_order, but f can stop the fold early by breaking, and whatever it
+    // breaks with is handed straight back, without visiting anything after it.
+    pub fn try_fold_in_order<B, R, F: FnMut(B, &V) -> ControlFlow<R, B>>(&self, init: B, mut f: F) -> ControlFlow<R, B> {
+        try_fold_subtree(&self.root, init, &mut f)
+    }
+
+    pub(crate) fn iter_sorted_mut<'a>(&'a mut self) -> SortedIteratorMut<'a, V> {
+        let mut iter = SortedIteratorMut {stack: Vec::new(), remaining: self.len};
+        iter.push_left_spine(&mut self.root);
+        iter
+    }
+
+    // Like iter_from, but mutable, and starting from the first value the predicate is
+    // true for, given one that's false up to some point and true from there on. Being
+    // a predicate rather than a value lets a map start from a key. Each node we go left
+    // past hands its value and its right subtree over to the stack, and everything else
+    // on the way down is left behind, so nothing is ever borrowed twice.
+    pub(crate) fn iter_sorted_mut_from<'a, F: Fn(&V) -> bool>(&'a mut self, at_or_after: F) -> SortedIteratorMut<'a, V> {
+        let (mut stack, mut skipped) = (Vec::new(), 0);
+        let mut tree = &mut self.root;
+        while let Some(ref mut node) = *tree {
+            let node = &mut **node;
+            if at_or_after(&node.value) {
+                stack.push((&mut node.value, &mut node.right));
+                tree = &mut node.left;
+            } else {
+                skipped += size(&node.left) + 1;
+                tree = &mut node.right;
+            }
+        }
+        SortedIteratorMut {stack: stack, remaining: self.len - skipped}
+    }
+
+    // Every pair of values next to each other in sorted order, smaller first, for
+    // things like finding the biggest gap between them. A tree with fewer than two
+    // values has no pairs.
+    pub fn pairs<'a>(&'a self) -> Pairs<'a, V> {
+        let mut values = self.iter_sorted();
+        let previous = values.next();
+        Pairs {values: values, previous: previous}
+    }
+
+    // The values in this tree or the other but not both, in ascending order, like
+    // BTreeSet::symmetric_difference.
+    pub fn symmetric_difference<'a>(&'a self, other: &'a Tree<V>) -> SymmetricDifference<'a, V> {
+        SymmetricDifference {left: self.iter_sorted().peekable(), right: other.iter_sorted().peekable()}
+    }
+
+    // Iterates over the values in ascending order, along with the depth of each one's
+    // node, counting the root as depth 0.
+    pub fn iter_with_depth<'a>(&'a self) -> DepthIterator<'a, V> {
+        let mut iter = DepthIterator {stack: Vec::new(), remaining: self.len};
+        iter.push_left_spine(&self.root, 0);
+        iter
+    }
+
+    // Moves every value out of the tree, in ascending order. The tree is emptied right
+    // away, and the Drain owns the nodes from then on, so the tree is empty whether or
+    // not the Drain gets used up.
+    pub fn drain(&mut self) -> Drain<V> {
+        self.len = 0;
+        Drain::new(self.root.take())
+    }
+
+    // Builds a balanced tree directly out of strictly ascending values in O(n), with
+    // no rotations, instead of inserting them one at a time.
+    pub fn from_sorted(values: Vec<V>) -> Tree<V> {
+        debug_assert!(values.windows(2).all(|pair| pair[0] < pair[1]), "values must be strictly ascending");
+
+        let len = values.len();
+        let mut tree = Tree {root: build_sorted(&mut values.into_iter(), len), len: len, #[cfg(feature = "rotation-count")] rotations: 0, policy: PhantomData};
+        tree.changed("from_sorted");
+        tree
+    }
+
+    // The same as from_sorted, but with the work split across rayon's threads. It
+    // builds exactly the same tree, so it's only worth it for big trees, and small
+    // ones are just built in place.
+    #[cfg(feature = "rayon")]
+    pub fn from_sorted_par(values: Vec<V>) -> Tree<V> where V: Send {
+        debug_assert!(values.windows(2).all(|pair| pair[0] < pair[1]), "values must be strictly ascending");
+
+        let len = values.len();
+        let mut values: Vec<Option<V>> = values.into_iter().map(Some).collect();
+        let mut tree = Tree {root: build_sorted_par(&mut values), len: len, #[cfg(feature = "rotation-count")] rotations: 0, policy: PhantomData};
+        tree.changed("from_sorted_par");
+        tree
+    }
+
+    // Builds a balanced tree from strictly ascending values as they come, without
+    // collecting them into a Vec first, so a big sorted file or database scan never
+    // has to be in memory twice. The length hint decides the shape of the tree, which
+    // gets built bottom up in one pass, in O(n). If the iterator runs out before then,
+    // the tree is smaller than planned, but still balanced. If it has more values
+    // than the hint said, the rest get inserted one at a time, which is correct but
+    // costs the usual O(log n) each.
+    //
+    // Once the iterator has run out, building carries on asking it for values for the
+    // subtrees still to come, and an iterator that isn't fused could start handing them
+    // out again, in the middle of the tree, so it gets fused first.
+    pub fn from_sorted_iter<I: Iterator<Item=V>>(values: I, len_hint: usize) -> Tree<V> {
+        let mut values = values.fuse();
+        let root = build_sorted_from(&mut values, len_hint);
+        let len = size(&root);
+        let mut tree = Tree {root: root, len: len, #[cfg(feature = "rotation-count")] rotations: 0, policy: PhantomData};
+        for value in values {
+            debug_assert!(tree.last().is_none_or(|last| *last < value), "values must be strictly ascending");
+            tree.insert_unchecked(value);
+        }
+        tree.changed("from_sorted_iter");
+        tree
+    }
+
+    // A new tree of f applied to every value. f has to be strictly increasing (if a < b
+    // then f(a) < f(b)), so the mapped values come out already in order, and the new
+    // tree can be built straight from them in O(n), instead of inserting them one by
+    // one. Debug builds check that they really are ascending, but release builds take
+    // f's word for it: if it isn't strictly increasing the new tree is out of order,
+    // and lookups in it can give wrong answers, but nothing worse than that.
+    pub fn map_monotonic<U: Ord, F: Fn(&V) -> U>(&self, f: F) -> Tree<U> {
+        Tree::from_sorted_iter(self.iter_sorted().map(f), self.len)
+    }
+
+    // The same as from_sorted, except that it checks the values really are strictly
+    // ascending first, in release builds too. If they aren't, we hand back the index of
+    // the first value that isn't greater than the one before it, along with the values
+    // themselves, so nothing is lost. Checking is one more O(n) pass, so this is still
+    // O(n) overall.
+    pub fn try_from_sorted(values: Vec<V>) -> Result<Tree<V>, (usize, Vec<V>)> {
+        match values.windows(2).position(|pair| pair[0] >= pair[1]) {
+            Some(index) => Err((index + 1, values)),
+            None => Ok(Tree::from_sorted(values))
+        }
+    }
+
+    // Keeps only the values the predicate returns true for, like BTreeSet::retain. We
+    // take the whole tree apart in order, filter, and build it back up again with
+    // from_sorted. That makes it O(n) no matter how much or how little is removed,
+    // which is worse than removing a single value in place, but means the result is
+    // perfectly balanced and we never rotate.
+    pub fn retain<F: FnMut(&V) -> bool>(&mut self, mut f: F) {
+        let retained: Vec<V> = into_sorted(self.root.take()).into_iter().filter(|value| f(value)).collect();
+        self.rebuild(retained, "retain");
+    }
+
+    // The same as retain, but the predicate can change the values it keeps. Changing
+    // where a value belongs in the order would break the tree, so this is only for the
+    // crate's own use, where the part a value is ordered by can't be changed (a map's
+    // keys, say).
+    pub(crate) fn retain_mut<F: FnMut(&mut V) -> bool>(&mut self, mut f: F) {
+        let mut values = into_sorted(self.root.take());
+        values.retain_mut(|value| f(value));
+        self.rebuild(values, "retain_mut");
+    }
+
+    // Removes every value the predicate is true for, and hands them back in ascending
+    // order, leaving the rest in the tree. Removing values one at a time while walking
+    // the tree would rotate nodes out from under the walk, so this works like retain:
+    // the whole tree comes apart in order, each value goes either to the ones handed
+    // back or to the ones kept, and the kept ones get built back up into a perfectly
+    // balanced tree. That all happens before this returns, so it's O(n) up front, and
+    // the tree has already lost the values whether or not the iterator gets used.
+    pub fn drain_filter<F: FnMut(&V) -> bool>(&mut self, mut pred: F) -> vec::IntoIter<V> {
+        let (drained, kept): (Vec<V>, Vec<V>) = into_sorted(self.root.take()).into_iter().partition(|value| pred(value));
+        self.rebuild(kept, "drain_filter");
+        drained.into_iter()
+    }
+
+    // Collapses runs of values that count as the same, keeping the first of each run,
+    // like Vec::dedup_by. Going through the values in ascending order, each one is
+    // passed to same along with the last value kept before it, as same(kept, value),
+    // and dropped if it returns true. Only neighbours in sorted order ever get
+    // compared, so same should agree with the ordering: if it says a is the same as c,
+    // it should say so for everything in between too. Like retain, this takes the tree
+    // apart and builds it back up with from_sorted, so it's O(n).
+    pub fn dedup_by<F: FnMut(&V, &V) -> bool>(&mut self, mut same: F) {
+        let mut values = into_sorted(self.root.take());
+        values.dedup_by(|value, kept| same(kept, value));
+        self.rebuild(values, "dedup_by");
+    }
+
+    // Inserts a whole batch of values at once. Inserting them one at a time costs
+    // O(k log n) for k values, and every one of those inserts walks from the root down
+    // to a leaf, which in a big tree is a cache miss at nearly every step, and then
+    // maybe rotates on the way back up. Instead we can sort the batch, merge it with the
+    // values already in the tree (which we get in order in O(n)) and build a perfectly
+    // balanced tree out of the result with from_sorted, which is O(n + k log k).
+    //
+    // Rebuilding touches every value in the tree, though, so it's only worth it when the
+    // batch is big compared to the tree. Walking down the tree costs a lot more per step
+    // than copying a value along during a rebuild does, so the cutoff here is that the
+    // batch has to be at least an eighth the size of the tree. That's a rough guess
+    // rather than a careful measurement, but it gets the big cases right either way:
+    // loading into an empty tree always rebuilds, and a handful of values added to a
+    // big tree never does.
+    //
+    // Either way, duplicates are handled just like insert: a value already in the tree
+    // stays, and of several equal values in the batch, the first one is kept.
+    pub fn bulk_insert(&mut self, items: Vec<V>) {
+        if !self.worth_rebuilding(items.len()) {
+            for value in items {
+                self.insert_unchecked(value);
+            }
+            self.changed("bulk_insert");
+            return
+        }
+
+        let mut items = items;
+        items.sort();
+        items.dedup();
+
+        let merged = union_sorted(into_sorted(self.root.take()), items.into_iter());
+        self.rebuild(merged, "bulk_insert");
+    }
+
+    // Whether a batch this big should be merged in with a rebuild, rather than inserted
+    // a value at a time: it has to be at least an eighth the size of the tree.
+    fn worth_rebuilding(&self, batch: usize) -> bool {
+        batch * 8 >= self.len
+    }
+
+    // Removes every value in the slice from the tree, handing back how many of them
+    // were actually there. The slice has to be strictly ascending, which is checked in
+    // debug builds. Like merge_sorted_slice, this walks the tree's values and the slice
+    // side by side, keeping the values the slice doesn't have, and builds a perfectly
+    // balanced tree out of what's left. That's O(n + k) for k values, rather than the
+    // O(k log n) of removing them one at a time, which for a big batch walks down the
+    // tree and rebalances on the way back up over and over. The slice only needs to be
+    // borrowed, since its values are only ever compared against.
+    pub fn remove_sorted_slice(&mut self, sorted: &[V]) -> usize {
+        debug_assert!(sorted.windows(2).all(|pair| pair[0] < pair[1]), "the slice must be strictly ascending");
+
+        let before = self.len;
+        let mut removing = sorted.iter().peekable();
+        let mut kept = into_sorted(self.root.take());
+        kept.retain(|value| {
+            while removing.peek().is_some_and(|&next| next < value) {
+                removing.next();
+            }
+            removing.peek() != Some(&value)
+        });
+        self.rebuild(kept, "remove_sorted_slice");
+        before - self.len
+    }
+
+    // The largest value less than or equal to this one. Every time we go right, the
+    // node we went right from is the best answer so far, and going left never is.
+    pub fn floor<Q: Ord+?Sized>(&self, value: &Q) -> Option<&V> where V: Borrow<Q> {
+        let (mut tree, mut best) = (&self.root, None);
+        while let Some(ref node) = *tree {
+            match value.cmp(node.value.borrow()) {
+                Ordering::Less => tree = &node.left,
+                Ordering::Greater => {
+                    best = Some(&node.value);
+                    tree = &node.right;
+                }
+                Ordering::Equal => return Some(&node.value)
+            }
+        }
+        best
+    }
+
+    // The smallest value greater than or equal to this one, the mirror image of floor.
+    pub fn ceiling<Q: Ord+?Sized>(&self, value: &Q) -> Option<&V> where V: Borrow<Q> {
+        let (mut tree, mut best) = (&self.root, None);
+        while let Some(ref node) = *tree {
+            match value.cmp(node.value.borrow()) {
+                Ordering::Less => {
+                    best = Some(&node.value);
+                    tree = &node.left;
+                }
+                Ordering::Greater => tree = &node.right,
+                Ordering::Equal => return Some(&node.value)
+            }
+        }
+        best
+    }
+
+    // The largest value strictly less than this one and the smallest strictly greater,
+    // found on the same way down, rather than with a floor and a ceiling that each
+    // walk the tree. Going right makes the node the best lower neighbour so far, and
+    // going left makes it the best upper one. If the value itself is in the tree, its
+    // neighbours are the ends of its subtrees, when it has them, or else whatever we
+    // passed on the way down.
+    pub fn neighbors<Q: Ord+?Sized>(&self, value: &Q) -> (Option<&V>, Option<&V>) where V: Borrow<Q> {
+        let (mut tree, mut lower, mut upper) = (&self.root, None, None);
+        while let Some(ref node) = *tree {
+            match value.cmp(node.value.borrow()) {
+                Ordering::Less => {
+                    upper = Some(&node.value);
+                    tree = &node.left;
+                }
+                Ordering::Greater => {
+                    lower = Some(&node.value);
+                    tree = &node.right;
+                }
+                Ordering::Equal => {
+                    return (node.left.as_ref().map(|left| left.rightmost()).or(lower),
+                            node.right.as_ref().map(|right| right.leftmost()).or(upper))
+                }
+            }
+        }
+        (lower, upper)
+    }
+
+    // The first value the predicate is false for, given a predicate that's true for
+    // every value up to some point in sorted order and false for every value after it,
+    // like |&value| value < 10. That's the same as what slices' partition_point finds,
+    // except this hands back the value rather than its position (which rank can find).
+    // If the predicate is true for a node's value, everything in its left subtree comes
+    // before it, so the first false value must be to its right. Otherwise, it's either
+    // this value or something to its left. Either way we only go down one side, so this
+    // is O(log n). If the predicate isn't like that, this gives back some value the
+    // predicate is false for, but not necessarily the first one.
+    pub fn partition_point<P: FnMut(&V) -> bool>(&self, mut pred: P) -> Option<&V> {
+        let mut found = None;
+        let mut tree = &self.root;
+        while let Some(ref node) = *tree {
+            if pred(&node.value) {
+                tree = &node.right;
+            } else {
+                found = Some(&node.value);
+                tree = &node.left;
+            }
+        }
+        found
+    }
+
+    // Batch versions of contains, for checking lots of values at once. For now these
+    // just look each value up on its own, which is O(k log n) for k values. Sorting the
+    // values first and walking them down the tree together could share the top part of
+    // each descent, but that's left for when it's needed.
+    pub fn contains_all(&self, values: &[V]) -> bool {
+        values.iter().all(|value| self.contains(value))
+    }
+
+    pub fn contains_any(&self, values: &[V]) -> bool {
+        values.iter().any(|value| self.contains(value))
+    }
+
+    // The values that aren't in the tree, in the order they were given.
+    pub fn missing<'a>(&self, values: &'a [V]) -> Vec<&'a V> {
+        values.iter().filter(|value| !self.contains(value)).collect()
+    }
+
+    // Inserts every value from the batch, one at a time, and says how many of them were
+    // new. The rest were duplicates, either of what was already in the tree or of an
+    // earlier value in the batch, and were dropped.
+    pub fn insert_many<I: IntoIterator<Item=V>>(&mut self, items: I) -> usize {
+        let mut inserted = 0;
+        for value in items {
+            if self.insert_unchecked(value) {
+                inserted += 1;
+            }
+        }
+        self.changed("insert_many");
+        inserted
+    }
+
+    // Inserts the value, but keeps the tree to at most max_len values, so it can serve
+    // as a bounded window onto the smallest (or largest) values seen so far. If the
+    // tree is already full, the largest or smallest value, whichever evict says, goes
+    // to make room, and is handed back. If the new value would itself be the one to go,
+    // because it's beyond that end of the tree, the tree is left alone and the new
+    // value is handed straight back instead. A value that's already in the tree is
+    // dropped, just like insert does, and nothing is evicted for it. Either way it's
+    // an insert and maybe a pop, so O(log n).
+    //
+    // Only the one value is ever evicted per call, to make room for the new one. So a
+    // tree that's already over max_len, because the cap came down since it filled up,
+    // say, stays as long as it was rather than shrinking to fit. Pop the extra values
+    // off first to bring it down to the new cap.
+    pub fn insert_capped(&mut self, value: V, max_len: usize, evict: Evict) -> Option<V> {
+        if self.contains(&value) {
+            return None
+        }
+        if self.len < max_len {
+            self.insert(value);
+            return None
+        }
+
+        let beyond_end = match evict {
+            Evict::Largest => self.last().is_none_or(|last| value > *last),
+            Evict::Smallest => self.first().is_none_or(|first| value < *first)
+        };
+        if beyond_end {
+            return Some(value)
+        }
+        self.insert(value);
+        match evict {
+            Evict::Largest => self.pop_last(),
+            Evict::Smallest => self.pop_first()
+        }
+    }
+
+    // The same as insert, except that if there isn't enough memory for the new node,
+    // this hands back the error rather than aborting the whole program. The node is
+    // the only thing an insert allocates, since rebalancing just moves existing nodes
+    // around, so we check whether the value's already there first, allocate the node
+    // only if it isn't, and once we have it nothing else can fail. The error is the
+    // allocator's AllocError rather than the TryReserveError collections use, since
+    // there's no capacity involved, just the one node. On failure the value is dropped,
+    // and the tree is left just as it was.
+    pub fn try_insert(&mut self, value: V) -> Result<bool, AllocError> {
+        if self.contains(&value) {
+            return Ok(false)
+        }
+
+        let spare = Box::try_new_uninit()?;
+        match self.root {
+            Some(ref mut root) => {
+                root.check_ordering(&value);
+                root.insert_with::<Avl>(value, false, Some(spare));
+            }
+            None => self.root = Some(new_leaf(value, Some(spare)))
+        }
+        self.len += 1;
+        self.changed("try_insert");
+        Ok(true)
+    }
+
+    // Puts the value in the tree, replacing any equal value that was already there,
+    // which gets handed back. This is for values that can be equal without being the
+    // same, like ones ordered by only one of their fields. Replacing a value doesn't
+    // change the shape of the tree, so nothing gets rebalanced when that happens.
+    pub fn replace(&mut self, value: V) -> Option<V> {
+        match self.root {
+            Some(ref mut root) => {
+                root.check_ordering(&value);
+                match root.insert_with::<Avl>(value, true, None) {
+                    InsertOutcome::Duplicate {value: old, ..} => {
+                        self.changed("replace");
+                        return Some(old)
+                    }
+                    InsertOutcome::Inserted {..} => ()
+                }
+            }
+            None => self.root = Some(Box::new(AvlTree::leaf(value)))
+        }
+        self.len += 1;
+        self.changed("replace");
+        None
+    }
+
+    // Returns the value in the tree equal to the one given, inserting it first if
+    // there isn't one, which is handy for interning. The catch is that inserting may
+    // rotate nodes on the way back up, and rotating swaps the contents of nodes, so a
+    // reference to the value taken on the way down wouldn't point at it anymore (and
+    // the borrow checker won't let us hold one across the rebalancing anyway). What
+    // doesn't change is the value's position in sorted order, which the insert works
+    // out as it goes, whether it finds an equal value or adds a new leaf. So once it's
+    // done and everything has settled, we look the value up by that position with nth,
+    // which goes straight down by the sizes in the nodes. That's the one descent to
+    // insert and one to find the value again, so it's O(log n).
+    pub fn get_or_insert(&mut self, value: V) -> &V {
+        let position = match self.root {
+            Some(ref mut root) => {
+                root.check_ordering(&value);
+                match root.insert_with::<Avl>(value, false, None) {
+                    InsertOutcome::Duplicate {position, ..} => position,
+                    InsertOutcome::Inserted {position, ..} => {
+                        self.len += 1;
+                        position
+                    }
+                }
+            }
+            None => {
+                self.root = Some(Box::new(AvlTree::leaf(value)));
+                self.len += 1;
+                0
+            }
+        };
+        self.changed("get_or_insert");
+        self.nth(position).expect("the value should be at the position the insert found for it")
+    }
+
+    // The same, except that the value is only made if there isn't an equal one there
+    // already, so looking up something that's already interned doesn't have to build a
+    // whole value just to throw it away. Like BTreeSet's, this panics if make gives
+    // back a value that isn't equal to the one being looked for, since it'd end up in
+    // the wrong place. It looks first, and only inserts (as get_or_insert does) when
+    // the value is missing, so it's still O(log n).
+    pub fn get_or_insert_with<Q: Ord+?Sized, F: FnOnce(&Q) -> V>(&mut self, value: &Q, make: F) -> &V where V: Borrow<Q> {
+        let mut position = 0;
+        let mut tree = &self.root;
+        while let Some(ref node) = *tree {
+            match value.cmp(node.value.borrow()) {
+                Ordering::Less => tree = &node.left,
+                Ordering::Greater => {
+                    position += size(&node.left) + 1;
+                    tree = &node.right;
+                }
+                Ordering::Equal => return self.nth(position + size(&node.left)).expect("the value is at its position")
+            }
+        }
+
+        let made = make(value);
+        assert!(made.borrow() == value, "get_or_insert_with made a value that isn't equal to the one looked for");
+        self.get_or_insert(made)
+    }
+
+    // Inserts the value if there isn't an equal one already, and either way returns the
+    // one that ends up stored in the tree. This is get_or_insert under the name people
+    // reach for when they're thinking of it as inserting, and costs the same O(log n).
+    pub fn insert_and_get(&mut self, value: V) -> &V {
+        self.get_or_insert(value)
+    }
+
+    // Lookups for trees whose values are ordered by only part of themselves, like the
+    // entries of a Map, which are ordered by their keys. Rather than a whole value to
+    // compare against, these take a function saying how whatever we're looking for
+    // compares to each value on the way down, so the caller doesn't need to make up a
+    // whole value just to search with. Anything changed through get_mut_by mustn't
+    // change how the value is ordered, or the tree won't be in order anymore, which is
+    // why these are only for use inside the crate.
+    pub(crate) fn get_mut_by<F: Fn(&V) -> Ordering>(&mut self, seek: F) -> Option<&mut V> {
+        let mut tree = &mut self.root;
+        while let Some(ref mut node) = *tree {
+            match seek(&node.value) {
+                Ordering::Less => tree = &mut node.left,
+                Ordering::Greater => tree = &mut node.right,
+                Ordering::Equal => return Some(&mut node.value)
+            }
+        }
+        None
+    }
+
+    pub(crate) fn remove_by<F: Fn(&V) -> Ordering>(&mut self, seek: F) -> Option<V> {
+        let removed = AvlTree::remove_by(&mut self.root, &seek);
+        if removed.is_some() {
+            self.len -= 1;
+        }
+        self.changed("remove_by");
+        removed
+    }
+
+    // Returns whether the value was actually there to remove.
+    pub fn remove<Q: Ord+?Sized>(&mut self, value: &Q) -> bool where V: Borrow<Q> {
+        self.take(value).is_some()
+    }
+
+    // Removes the value equal to this one, like remove does, but hands back the value
+    // that was in the tree, like BTreeSet::take. Equal values aren't necessarily
+    // identical, so this is how to get back the one that was stored.
+    pub fn take<Q: Ord+?Sized>(&mut self, value: &Q) -> Option<V> where V: Borrow<Q> {
+        let removed = AvlTree::remove(&mut self.root, value);
+        if removed.is_some() {
+            self.len -= 1;
+        }
+        self.changed("take");
+        removed
+    }
+
+    // Concatenates two trees, where every element of left is less than every element
+    // of right. We borrow the largest element of left to act as the pivot between
+    // the two, which costs one O(log n) removal, and then hang the shorter tree off
+    // the appropriate spine of the taller one. This is the classic AVL join, and it's
+    // what split is built out of.
+    pub fn join(mut left: Tree<V>, mut right: Tree<V>) -> Tree<V> {
+        debug_assert!(match (&left.root, &right.root) {
+            (Some(left), Some(right)) => left.rightmost() < right.leftmost(),
+            _ => true
+        }, "every element of the left tree must be less than every element of the right tree");
+
+        let len = left.len + right.len;
+        let mut joined = Tree {root: AvlTree::join(left.root.take(), right.root.take()), len: len, #[cfg(feature = "rotation-count")] rotations: left.rotations + right.rotations, policy: PhantomData};
+        joined.changed("join");
+        joined
+    }
+
+    // Removes every value within some bounds. Rather than removing them one at a time,
+    // we split off everything below the range, then split what's left into the range
+    // and everything above it, and join the parts below and above back together. The
+    // splits and the join are each O(log n), and then the k values in the range get
+    // dropped, so this is O(log n + k).
+    pub fn remove_range<R: RangeBounds<V>>(&mut self, bounds: R) {
+        let (below, rest) = AvlTree::split_by(self.root.take(), &|value: &V| !after_start(bounds.start_bound(), value));
+        let (range, above) = AvlTree::split_by(rest, &|value: &V| before_end(bounds.end_bound(), value));
+        dismantle(range);
+
+        self.len = size(&below) + size(&above);
+        self.root = AvlTree::join(below, above);
+        self.changed("remove_range");
+    }
+
+    // The opposite of remove_range: drops everything outside the bounds, and keeps
+    // what's inside. It's the same two splits, except that it's the pieces below and
+    // above the range that get dismantled, and the range that's kept, so there's no
+    // join at all.
+    pub fn retain_range<R: RangeBounds<V>>(&mut self, bounds: R) {
+        let (below, rest) = AvlTree::split_by(self.root.take(), &|value: &V| !after_start(bounds.start_bound(), value));
+        let (range, above) = AvlTree::split_by(rest, &|value: &V| before_end(bounds.end_bound(), value));
+        dismantle(below);
+        dismantle(above);
+
+        self.len = size(&range);
+        self.root = range;
+        self.changed("retain_range");
+    }
+
+    // Moves every value from the key up out of this tree and into a new one, like
+    // BTreeSet::split_off, leaving just the values less than the key. The split is the
+    // same O(log n) one remove_range does, and the sizes the nodes keep give each half's
+    // length without counting.
+    pub fn split_off(&mut self, key: &V) -> Tree<V> {
+        let (below, above) = AvlTree::split_by(self.root.take(), &|value: &V| value < key);
+        self.len = size(&below);
+        self.root = below;
+        self.changed("split_off");
+
+        let len = size(&above);
+        let mut split = Tree {root: above, len: len, #[cfg(feature = "rotation-count")] rotations: 0, policy: PhantomData};
+        split.changed("split_off");
+        split
+    }
+
+    // Moves every value out of other and into this tree, leaving other empty. When a
+    // value is in both trees, the one already in this tree is kept, and other's is
+    // dropped, the same as inserting it would. If every value of one tree is less than
+    // every value of the other, the two can just be joined, which is O(log n).
+    // Otherwise the values from other are inserted one by one. Joining takes other's
+    // nodes, and its rotation count goes along with them, the same as in Tree::join.
+    pub fn append(&mut self, other: &mut Tree<V>) {
+        let below = match (self.first(), self.last(), other.first(), other.last()) {
+            (Some(first), Some(last), Some(other_first), Some(other_last)) => {
+                if *last < *other_first {
+                    Some(false)
+                } else if *other_last < *first {
+                    Some(true)
+                } else {
+                    None
+                }
+            }
+            _ => Some(false)
+        };
+
+        match below {
+            Some(other_is_below) => {
+                let (left, right) = if other_is_below {
+                    (other.root.take(), self.root.take())
+                } else {
+                    (self.root.take(), other.root.take())
+                };
+                self.root = AvlTree::join(left, right);
+                self.len += other.len;
+                other.len = 0;
+                #[cfg(feature = "rotation-count")]
+                {
+                    self.rotations += mem::replace(&mut other.rotations, 0);
+                }
+                self.changed("append");
+            }
+            None => {
+                for value in other.drain() {
+                    self.insert_unchecked(value);
+                }
+                self.changed("append");
+            }
+        }
+    }
+}
+
+impl <V: Ord+Clone> Tree<V> {
+    // A frozen copy of the tree as it is now, which can be kept around, cloned for
+    // free, and iterated over while this tree carries on changing. The mutable tree's
+    // nodes are Boxes, with exactly one owner each, so they can't be shared with a
+    // snapshot: if they were, the next insert would rotate nodes out from under it.
+    // Instead the values are copied out into a fresh, perfectly balanced persistent
+    // tree, whose Rc nodes belong to the snapshot alone, so nothing one of them does
+    // can affect the other. That makes taking a snapshot O(n), but once taken it's as
+    // cheap to clone and update as any other persistent tree.
+    pub fn snapshot(&self) -> PersistentTree<V> {
+        PersistentTree::from_sorted(self.iter_sorted().cloned(), self.len)
+    }
+
+    // A copy of the tree that's perfectly balanced, whatever shape this one is in.
+    // Cloning copies the nodes exactly as they are, leaning however the history of
+    // inserts and removes left them, which is within one level of balanced but not
+    // necessarily as short as it could be. This instead copies the values out in order
+    // and builds a fresh tree out of them with from_sorted. Both are O(n).
+    pub fn clone_balanced(&self) -> Tree<V> {
+        Tree::from_sorted_iter(self.iter_sorted().cloned(), self.len)
+    }
+
+    // Adds every value in the slice to the tree, so the tree ends up holding the union
+    // of the two. The slice has to be strictly ascending already, which is checked in
+    // debug builds. Rather than inserting each value, this merges the slice with the
+    // tree's values in order and builds a perfectly balanced tree out of the result,
+    // which is O(n + k) for k values, however big the batch is. A value that's already
+    // in the tree stays, and the slice's copy of it is skipped. Unlike bulk_insert,
+    // there's nothing to sort, but it always rebuilds, so for a handful of values
+    // added to a big tree, inserting them is quicker.
+    pub fn merge_sorted_slice(&mut self, sorted: &[V]) {
+        debug_assert!(sorted.windows(2).all(|pair| pair[0] < pair[1]), "the slice must be strictly ascending");
+
+        let merged = union_sorted(into_sorted(self.root.take()), sorted.iter().cloned());
+        self.rebuild(merged, "merge_sorted_slice");
+    }
+
+    // Adds a copy of every value in the slice, which doesn't have to be sorted, for when
+    // all there is to hand is a borrowed slice. It takes whichever of bulk_insert's two
+    // routes it would, with the same cutoff: a slice at least an eighth the size of the
+    // tree is copied into a Vec, sorted and merged in with one rebuild, while anything
+    // smaller has each value copied and inserted on its own, without the Vec.
+    // Duplicates are handled just like insert too.
+    pub fn extend_from_slice(&mut self, items: &[V]) {
+        if self.worth_rebuilding(items.len()) {
+            self.bulk_insert(items.to_vec());
+        } else {
+            for value in items {
+                self.insert_unchecked(value.clone());
+            }
+            self.changed("extend_from_slice");
+        }
+    }
+}
+
+// An exact copy, node for node. A new tree starts its rotation count over.
+impl <V: Ord+Clone> Clone for Tree<V> {
+    fn clone(&self) -> Self {
+        Tree {root: self.root.clone(), len: self.len, #[cfg(feature = "rotation-count")] rotations: 0, policy: PhantomData}
+    }
+}
+
+impl <V: Ord+Debug, P: BalancePolicy> Tree<V, P> {
+    // Checks everything that makes this a valid AVL tree, at every node: values in
+    // left subtrees are smaller and values in right subtrees are bigger, the height
+    // metadata matches the actual heights of the children, and those heights never
+    // differ by more than one. Trees that don't rebalance don't keep heights, so for
+    // them it's only the ordering. These are the same things the quickcheck properties
+    // check, plus the subtree sizes each node keeps, but anyone extending the tree can
+    // call this after their own operations.
+    // On failure, the error describes the first problem found and the node it's at.
+    pub fn check_invariants(&self) -> Result<(), String> {
+        self.first_violation(&|node| format!("{:?}", node.value))
+    }
+}
+
+fn same_structure<V: Ord>(tree: &Option<Box<AvlTree<V>>>, other: &Option<Box<AvlTree<V>>>) -> bool {
+    match (tree, other) {
+        (Some(node), Some(other)) => {
+            node.metadata == other.metadata && node.size == other.size && node.value == other.value &&
+                same_structure(&node.left, &other.left) && same_structure(&node.right, &other.right)
+        }
+        (None, None) => true,
+        _ => false
+    }
+}
+
+// Checks the subtree is a valid AVL tree with every value strictly between the bounds,
+// returning how many values it has if so. It recurses rather than keeping a stack of
+// its own, so it never allocates, and the recursion only goes as deep as the tree.
+fn valid_subtree<V: Ord>(tree: &Option<Box<AvlTree<V>>>, lower: Option<&V>, upper: Option<&V>) -> Option<usize> {
+    let tree = match *tree {
+        Some(ref tree) => tree,
+        None => return Some(0)
+    };
+
+    // Corrupted metadata can be anything at all, so nothing here can do arithmetic on
+    // it that might overflow until it's been checked against the children, and a child
+    // claiming to be as tall as a height can go is as wrong as any other mistake.
+    let child_height = |child: &Option<Box<AvlTree<V>>>| match *child {
+        Some(ref child) => max(child.metadata.0, child.metadata.1).checked_add(1),
+        None => Some(0)
+    };
+    let in_bounds = lower.is_none_or(|lower| tree.value > *lower) && upper.is_none_or(|upper| tree.value < *upper);
+    if !in_bounds || tree.metadata != (child_height(&tree.left)?, child_height(&tree.right)?) {
+        return None
+    }
+    if !(-1..=1).contains(&lean(&tree.metadata)) {
+        return None
+    }
+
+    let left = valid_subtree(&tree.left, lower, Some(&tree.value))?;
+    let right = valid_subtree(&tree.right, Some(&tree.value), upper)?;
+    if tree.size == 1 + left + right { Some(tree.size) } else { None }
+}
+
+// Recomputes the metadata and size of every node in the subtree from the bottom up,
+// returning how many nodes had them wrong.
+fn repair_subtree<V: Ord>(tree: &mut Option<Box<AvlTree<V>>>) -> usize {
+    let tree = match *tree {
+        Some(ref mut tree) => tree,
+        None => return 0
+    };
+    let corrections = repair_subtree(&mut tree.left) + repair_subtree(&mut tree.right);
+    let before = (tree.metadata, tree.size);
+    tree.fix_metadata();
+    corrections + if (tree.metadata, tree.size) == before { 0 } else { 1 }
+}
+
+impl <V: Ord> Tree<V> {
+    // Puts a broken tree right again, where check_invariants would only say what's
+    // wrong with it. First every node's metadata and size are recomputed from the
+    // bottom up, which is all it takes if that's the only thing that was corrupted,
+    // and each node that had them wrong counts as one correction, as does a wrong
+    // length. If the tree still isn't valid after that, because nodes are out of
+    // balance or values are out of order, there's no fixing it node by node, so it's
+    // rebuilt from its values, sorted and deduplicated, and that counts as one more.
+    // Either way it's O(n), or O(n log n) if it has to sort.
+    pub fn repair(&mut self) -> usize {
+        let mut corrections = repair_subtree(&mut self.root);
+        let len = size(&self.root);
+        if len != self.len {
+            self.len = len;
+            corrections += 1;
+        }
+
+        if self.is_valid_avl() {
+            self.changed("repair");
+            return corrections
+        }
+        let mut values = into_sorted(self.root.take());
+        values.sort();
+        values.dedup();
+        self.rebuild(values, "repair");
+        corrections + 1
+    }
+
+    // The same checks as check_invariants, but just saying whether they all hold, so
+    // it doesn't need the values to be Debug, and doesn't allocate to describe what
+    // went wrong. Handy for fuzzing, where all that matters is whether it's true.
+    pub fn is_valid_avl(&self) -> bool {
+        valid_subtree(&self.root, None, None) == Some(self.len)
+    }
+
+}
+
+impl <V: Ord, P: BalancePolicy> Tree<V, P> {
+    // Does the actual checking for check_invariants, describing nodes however it's
+    // told to, since without Debug there's no way to print their values.
+    //
+    // Each node is checked against the tightest bounds its ancestors put on it, rather
+    // than against every value in its subtrees, so this is O(n).
+    fn first_violation(&self, describe: &dyn Fn(&AvlTree<V>) -> String) -> Result<(), String> {
+        let mut count = 0;
+        // Each node still to check, with the nearest ancestors it's to the right and to
+        // the left of, which are the bounds its value has to be between.
+        type Bounded<'a, V> = (&'a AvlTree<V>, Option<&'a AvlTree<V>>, Option<&'a AvlTree<V>>);
+        let mut to_check: Vec<Bounded<V>> = self.root.iter().map(|root| (&**root, None, None)).collect();
+        while let Some((tree, lower, upper)) = to_check.pop() {
+            count += 1;
+
+            if let Some(lower) = lower {
+                if tree.value <= lower.value {
+                    return Err(format!("node {} is in the right subtree of {} but isn't greater than it", describe(tree), describe(lower)))
+                }
+            }
+            if let Some(upper) = upper {
+                if tree.value >= upper.value {
+                    return Err(format!("node {} is in the left subtree of {} but isn't less than it", describe(tree), describe(upper)))
+                }
+            }
+
+            let heights = if P::REBALANCES { (height(&tree.left), height(&tree.right)) } else { (0, 0) };
+            if tree.metadata != heights {
+                return Err(format!("node {} has metadata {:?} but its children have heights {:?}", describe(tree), tree.metadata, heights))
+            }
+            let subtree_size = 1 + size(&tree.left) + size(&tree.right);
+            if tree.size != subtree_size {
+                return Err(format!("node {} has size {} but its subtree has {} values", describe(tree), tree.size, subtree_size))
+            }
+            if P::REBALANCES && (tree.metadata.0 - tree.metadata.1 > 1 || tree.metadata.0 - tree.metadata.1 < -1) {
+                return Err(format!("node {} is unbalanced, its children have heights {:?}", describe(tree), tree.metadata))
+            }
+
+            if let Some(ref left) = tree.left {
+                to_check.push((left, lower, Some(tree)));
+            }
+            if let Some(ref right) = tree.right {
+                to_check.push((right, Some(tree), upper));
+            }
+        }
+
+        if count != self.len {
+            return Err(format!("the tree has {} nodes but its length is {}", count, self.len))
+        }
+        Ok(())
+    }
+
+    // Where a node comes in an in-order walk of the tree. In a broken tree that isn't
+    // necessarily its value's place in sorted order, but it's still a way to say which
+    // node we mean without printing its value.
+    #[cfg(debug_assertions)]
+    fn position_of(&self, node: &AvlTree<V>) -> usize {
+        let mut stack: Vec<&AvlTree<V>> = Vec::new();
+        let mut next = self.root.as_deref();
+        let mut position = 0;
+        loop {
+            while let Some(tree) = next {
+                stack.push(tree);
+                next = tree.left.as_deref();
+            }
+            match stack.pop() {
+                Some(tree) if ptr::eq(tree, node) => return position,
+                Some(tree) => {
+                    position += 1;
+                    next = tree.right.as_deref();
+                }
+                None => return position
+            }
+        }
+    }
+
+    // Every public operation that changes the tree ends by calling this, which is where
+    // the rotations it did get counted, and where debug builds check it didn't break
+    // anything.
+    fn changed(&mut self, operation: &str) {
+        #[cfg(feature = "rotation-count")]
+        {
+            self.rotations += PENDING_ROTATIONS.with(|pending| pending.replace(0));
+        }
+        self.debug_check(operation);
+    }
+
+    // Replaces everything in the tree with a perfectly balanced tree of the values,
+    // which have to be strictly ascending. It's still the same tree, so it keeps its
+    // rotation count.
+    fn rebuild(&mut self, values: Vec<V>, operation: &str) {
+        debug_assert!(values.windows(2).all(|pair| pair[0] < pair[1]), "values must be strictly ascending");
+
+        let len = values.len();
+        dismantle(self.root.take());
+        self.root = build_sorted(&mut values.into_iter(), len);
+        self.len = len;
+        self.changed(operation);
+    }
+
+    // In debug builds, every public operation that changes the tree checks it's still
+    // valid afterwards, and panics on the spot if not. A bug in the balancing logic
+    // shows up as a bad decision somewhere down the line, far from the mistake that
+    // caused it, so catching it right away saves a lot of head scratching. The nodes
+    // are described by their position in the tree, since their values might not be
+    // printable. This makes every change O(n), which is fine for tests but not for real
+    // work, so in release builds it compiles away to nothing.
+    //
+    // Trees that don't rebalance skip it. Their tests insert thousands of values, and
+    // checking the whole tree after every one makes that quadratic, so they check the
+    // tree once at the end instead.
+    #[cfg(debug_assertions)]
+    fn debug_check(&self, operation: &str) {
+        if !P::REBALANCES {
+            return
+        }
+        if let Err(problem) = self.first_violation(&|node| format!("#{}", self.position_of(node))) {
+            panic!("{} broke the tree: {} (nodes are numbered in sorted order)", operation, problem)
+        }
+        self.assert_height_bound();
+    }
+
+    // In debug builds, panics if the tree is taller than any AVL tree with this many
+    // values could be, which is the guarantee that no order of inserts and removes can
+    // make lookups degrade. If balancing is correct at every node this always holds,
+    // but it's a cheap, O(log n), check that catches a tree quietly going lopsided
+    // even when nothing else has been checked. Release builds skip it, and so do
+    // trees that don't rebalance, since they have no such guarantee.
+    pub fn assert_height_bound(&self) {
+        debug_assert!(!P::REBALANCES || height(&self.root) as usize <= height_bound(self.len),
+                      "a tree of {} values has height {}, but can be at most {} tall", self.len, height(&self.root), height_bound(self.len));
+    }
+
+    #[cfg(not(debug_assertions))]
+    fn debug_check(&self, _: &str) {}
+}
+
+// A tree whose every node also keeps a summary of its subtree, of whatever kind the
+// Augment says, like the sum of its values, or the smallest of some secondary key.
+// It's balanced just like Tree, by the very same code, which keeps the summaries up
+// to date along with the heights, whenever a node changes or rotates, so they cost
+// O(1) per node touched and nothing more. For now it does the basics, inserting,
+// removing and looking values up, plus handing back the summary of the whole tree;
+// searches that steer by the summaries on the way down are what this is for.
+pub struct AugmentedTree<V: Ord, A: Augment<V>> {
+    root: Option<Box<BinaryTree<V, Augmented<A>>>>,
+    len: usize
+}
+
+impl <V: Ord, A: Augment<V>> Default for AugmentedTree<V, A> {
+    fn default() -> Self {
+        AugmentedTree::new()
+    }
+}
+
+impl <V: Ord, A: Augment<V>> Drop for AugmentedTree<V, A> {
+    fn drop(&mut self) {
+        dismantle(self.root.take());
+    }
+}
+
+impl <V: Ord, A: Augment<V>> AugmentedTree<V, A> {
+    pub fn new() -> Self {
+        AugmentedTree {root: None, len: 0}
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    // The summary of every value in the tree, or None if it's empty.
+    pub fn summary(&self) -> Option<&A> {
+        self.root.as_ref().map(|root| &root.metadata.summary)
+    }
+
+    pub fn contains<Q: Ord+?Sized>(&self, value: &Q) -> bool where V: Borrow<Q> {
+        let mut tree = &self.root;
+        while let Some(ref node) = *tree {
+            match value.cmp(node.value.borrow()) {
+                Ordering::Less => tree = &node.left,
+                Ordering::Greater => tree = &node.right,
+                Ordering::Equal => return true
+            }
+        }
+        false
+    }
+
+    // Returns whether the value was actually added, i.e. false if it was already in
+    // the tree.
+    pub fn insert(&mut self, value: V) -> bool {
+        let added = match self.root {
+            Some(ref mut root) => {
+                root.check_ordering(&value);
+                match root.insert_with::<Avl>(value, false, None) {
+                    InsertOutcome::Duplicate {..} => false,
+                    InsertOutcome::Inserted {..} => true
+                }
+            }
+            None => {
+                self.root = Some(Box::new(BinaryTree::leaf(value)));
+                true
+            }
+        };
+        if added {
+            self.len += 1;
+        }
+        discard_rotations();
+        added
+    }
+
+    pub fn remove<Q: Ord+?Sized>(&mut self, value: &Q) -> Option<V> where V: Borrow<Q> {
+        let removed = BinaryTree::remove(&mut self.root, value);
+        if removed.is_some() {
+            self.len -= 1;
+        }
+        discard_rotations();
+        removed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use quickcheck::{Arbitrary, Gen};
+
+    // An arbitrary tree is formed by starting with an empty tree and inserting an arbitrary
+    // number of arbitrary values. Generating the tree any other way would defeat the purpose
+    // of the property based tests.
+    impl Arbitrary for BinaryTree<i32, (i8, i8)> {
+        fn arbitrary<G: Gen>(g: &mut G) -> Self {
+            let mut tree = AvlTree::leaf(g.gen_range(-1000,1000));
+            while g.gen() {
+                tree.insert::<Avl>(g.gen_range(-1000,1000));
+            }
+            tree
+        }
+
+        // Shrinks by removing one value at a time, giving every tree with exactly one
+        // value gone. Removing keeps the trees valid, and like the arbitrary trees they
+        // only ever come from inserting and removing. A tree with one value can't get
+        // any smaller, since a node always holds a value.
+        fn shrink(&self) -> Box<dyn Iterator<Item=Self>> {
+            let tree = self.clone();
+            Box::new(in_order(self).into_iter().filter_map(move |value| {
+                let mut root = Some(Box::new(tree.clone()));
+                AvlTree::remove(&mut root, &value);
+                root.map(|root| *root)
+            }))
+        }
+    }
+
+    // The shape of a tree depends on the order its values were inserted in, not just on
+    // what the values are, so reproducing a bug in balancing often means reproducing the
+    // exact order. This inserts the values in an order shuffled by a little xorshift
+    // generator, so the same values and seed always give the same tree, and a seed that
+    // turns up a problem can be written straight into a test. The seed goes through a
+    // step of splitmix64 first, so that every seed gives its own shuffle, even ones
+    // that only differ in a bit or two. Xorshift gets stuck at zero, so the one seed
+    // that mixes to zero starts from a fixed state instead.
+    fn from_shuffled<V: Ord>(items: Vec<V>, seed: u64) -> Tree<V> {
+        let mut items = items;
+        let mut state = seed.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        state = (state ^ (state >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        state = (state ^ (state >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        state ^= state >> 31;
+        if state == 0 {
+            state = 0x2545_f491_4f6c_dd1d;
+        }
+        for i in (1..items.len()).rev() {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            items.swap(i, (state % (i as u64 + 1)) as usize);
+        }
+
+        let mut tree = Tree::new();
+        for item in items {
+            tree.insert(item);
+        }
+        tree
+    }
+
+    // The same seed should always build exactly the same tree, and every seed should
+    // build a valid one.
+    #[quickcheck]
+    fn from_shuffled_property(values: Vec<i32>, seed: u64) -> bool {
+        let tree = from_shuffled(values.clone(), seed);
+        let again = from_shuffled(values.clone(), seed);
+        format!("{:?}", tree) == format!("{:?}", again) && tree.check_invariants().is_ok() &&
+            tree.len() == values.iter().collect::<std::collections::BTreeSet<_>>().len()
+    }
+
+    // Seeds that only differ in their lowest bit should still shuffle differently.
+    #[test]
+    fn from_shuffled_tells_neighbouring_seeds_apart() {
+        for seed in [0, 2, 42] {
+            let tree = from_shuffled((0..64).collect(), seed);
+            let next = from_shuffled((0..64).collect(), seed + 1);
+            assert!(!tree.structurally_eq(&next));
+        }
+    }
+
+    // The iterator stuff is only used in the quickcheck properties. Specifically for
+    // checking ordering.
+    impl <'a, V: Ord, M> BinaryTree<V, M> {
+        fn iter(&'a self) -> BinaryTreeIterator<'a, V, M> {
+            BinaryTreeIterator {to_visit: vec![&self]}
+        }
+    }
+
+    struct BinaryTreeIterator<'a, V: 'a+Ord, M: 'a> {
+        to_visit: Vec<&'a BinaryTree<V, M>>
+    }
+
+    impl <'a, V: 'a+Ord, M: 'a> Iterator for BinaryTreeIterator<'a, V, M> {
+        type Item = &'a BinaryTree<V, M>;
+
+        // Do depth first search as an iterator.
+        fn next(&mut self) -> Option<&'a BinaryTree<V, M>> {
+            let ret = self.to_visit.pop();
+            match ret {
+                Some(&BinaryTree {left: Some(ref left), right: None, ..}) => {self.to_visit.push(left)},
+                Some(&BinaryTree {left: None, right: Some(ref right), ..}) => {self.to_visit.push(right)},
+                Some(&BinaryTree {left: Some(ref left), right: Some(ref right), ..}) => {
+                    self.to_visit.push(left);
+                    self.to_visit.push(right)
+                },
+                _ => ()
+            }
+            ret
+        }
+    }
+
+    // This property ensures that all elements to the left of the node we're handed are less
+    // than the value that node contains, and all elements to the right greater than. Equal
+    // is not handled as duplicates are not allowed.
+    #[quickcheck]
+    fn ordering_property(bt: BinaryTree<i32, (i8, i8)>) -> bool {
+        match bt {
+            BinaryTree {metadata: _, value, left: Some(ref left), right: Some(ref right), ..} => {
+                return left.iter().all(|t| value > t.value) && right.iter().all(|t| value < t.value)
+            },
+            BinaryTree {metadata: _, value, left: None, right: Some(ref right), ..} => {
+                return right.iter().all(|t| value < t.value)
+            },
+            BinaryTree {metadata: _, value, left: Some(ref left), right: None, ..} => {
+                return left.iter().all(|t| value > t.value)
+            },
+            _ => true
+        }
+    }
+
+    // This property recursively walks the tree and verifies that the height metadata is
+    // in correspondence with the recursive calculation of height.
+    #[quickcheck]
+    fn height_is_maintained(bt: AvlTree<i32>) -> bool {
+        match bt {
+            BinaryTree {
+                metadata: (ref left_height, ref right_height), value: _,
+                left: Some(box BinaryTree {metadata: (ref left_left_height, ref left_right_height), ..}),
+                right: Some(box BinaryTree {metadata: (ref right_left_height, ref right_right_height), ..}), ..}
+            => {
+                *left_height == std::cmp::max(*left_left_height, *left_right_height) + 1 && *right_height == std::cmp::max(*right_left_height, *right_right_height) + 1
+            },
+            BinaryTree {
+                metadata: (ref left_height, ref right_height), value: _,
+                right: Some(box BinaryTree {metadata: (ref right_left_height, ref right_right_height), ..}),
+                left: None, ..}
+            => {
+                *right_height == std::cmp::max(*right_left_height, *right_right_height) + 1 && *left_height == 0
+            },
+            BinaryTree {
+                metadata: (ref left_height, ref right_height), value: _,
+                left: Some(box BinaryTree {metadata: (ref left_left_height, ref left_right_height), ..}),
+                right: None, ..}
+            => {
+                *left_height == std::cmp::max(*left_left_height, *left_right_height) + 1 && *right_height == 0
+            },
+            BinaryTree {metadata: (ref left_height, ref right_height), value: _, left: None, right: None, ..} => {
+                *left_height == 0 && *right_height == 0
+            }
+        }
+    }
+
+    // This property verifies that, for the node we're given, the height metadata never
+    // differs by more than 1. This is the definition of a tree being balanced.
+    #[quickcheck]
+    fn balance_property(bt: BinaryTree<i32, (i8, i8)>) -> bool {
+        match bt {
+            BinaryTree {metadata, ..} => ( metadata.0 - metadata.1 ) <= 1 && ( metadata.0 - metadata.1 ) >= -1
+        }
+    }
+
+    fn in_order(bt: &AvlTree<i32>) -> Vec<i32> {
+        let mut values = bt.left.as_ref().map_or(Vec::new(), |left| in_order(left));
+        values.push(bt.value);
+        values.extend(bt.right.as_ref().map_or(Vec::new(), |right| in_order(right)));
+        values
+    }
+
+    fn metadata_is_correct(bt: &AvlTree<i32>) -> bool {
+        bt.iter().all(|tree| {
+            tree.metadata == (height(&tree.left), height(&tree.right)) && tree.size == 1 + size(&tree.left) + size(&tree.right)
+        })
+    }
+
+    // Rotating shuffles which node is where, but it should never change the order the
+    // values are in, and every node should come out with its metadata recomputed.
+    // Rotating back the other way should then give back exactly the tree we started
+    // with. A rotation needs a child on the side it rotates from, so trees without one
+    // pass trivially.
+    #[quickcheck]
+    fn rotate_left_property(bt: BinaryTree<i32, (i8, i8)>) -> bool {
+        if bt.right.is_none() {
+            return true
+        }
+        let (before, drawn) = (in_order(&bt), format!("{:?}", bt));
+        let mut bt = bt;
+        bt.rotate_left();
+        let rotated_ok = in_order(&bt) == before && metadata_is_correct(&bt) && bt.left.is_some();
+        bt.rotate_right();
+        rotated_ok && format!("{:?}", bt) == drawn
+    }
+
+    #[quickcheck]
+    fn rotate_right_property(bt: BinaryTree<i32, (i8, i8)>) -> bool {
+        if bt.left.is_none() {
+            return true
+        }
+        let (before, drawn) = (in_order(&bt), format!("{:?}", bt));
+        let mut bt = bt;
+        bt.rotate_right();
+        let rotated_ok = in_order(&bt) == before && metadata_is_correct(&bt) && bt.right.is_some();
+        bt.rotate_left();
+        rotated_ok && format!("{:?}", bt) == drawn
+    }
+
+    // Any summary that can be built from the children's works as metadata, like one that
+    // keeps track of the smallest value in the subtree.
+    #[derive(Debug, PartialEq)]
+    struct Smallest(i32);
+
+    impl Augment<i32> for Smallest {
+        fn leaf(value: &i32) -> Self {
+            Smallest(*value)
+        }
+
+        fn combine(left: Option<&Smallest>, value: &i32, right: Option<&Smallest>) -> Self {
+            let children = left.iter().chain(right.iter()).map(|smallest| smallest.0);
+            Smallest(children.fold(*value, std::cmp::min))
+        }
+    }
+
+    #[test]
+    fn other_metadata_is_maintained() {
+        let mut tree: BinaryTree<i32, Smallest> = BinaryTree::leaf(5);
+        assert_eq!(Smallest(5), tree.metadata);
+
+        tree.right = Some(Box::new(BinaryTree::leaf(3)));
+        tree.fix_metadata();
+        assert_eq!(Smallest(3), tree.metadata);
+
+        tree.left = Some(Box::new(BinaryTree::leaf(-1)));
+        tree.fix_metadata();
+        assert_eq!(Smallest(-1), tree.metadata);
+        assert_eq!(3, tree.size);
+    }
+
+    // The total of the values in a subtree, as a summary anyone could write.
+    #[derive(Debug, PartialEq)]
+    struct Sum(i64);
+
+    impl Augment<i32> for Sum {
+        fn leaf(value: &i32) -> Self {
+            Sum(*value as i64)
+        }
+
+        fn combine(left: Option<&Sum>, value: &i32, right: Option<&Sum>) -> Self {
+            Sum(left.map_or(0, |left| left.0) + *value as i64 + right.map_or(0, |right| right.0))
+        }
+    }
+
+    // However the values go in and come out, every node's summary should be what it
+    // would be worked out from scratch, and the tree should stay balanced.
+    #[quickcheck]
+    fn augmented_tree_property(values: Vec<i32>, removals: Vec<i32>) -> bool {
+        let mut tree: AugmentedTree<i32, Sum> = AugmentedTree::new();
+        let mut reference = std::collections::BTreeSet::new();
+        let inserted_ok = values.iter().all(|&value| tree.insert(value) == reference.insert(value));
+        let removed_ok = removals.iter().chain(values.iter().step_by(3)).all(|value| {
+            tree.remove(value).is_some() == reference.remove(value)
+        });
+
+        let nodes_ok = tree.root.as_ref().map_or(true, |root| root.iter().all(|node| {
+            let total: i64 = node.iter().map(|node| node.value as i64).sum();
+            node.metadata.summary == Sum(total) && lean(&node.metadata) >= -1 && lean(&node.metadata) <= 1 &&
+                node.metadata.heights == (height(&node.left), height(&node.right))
+        }));
+        inserted_ok && removed_ok && nodes_ok && tree.len() == reference.len() &&
+            tree.summary().map_or(0, |sum| sum.0) == reference.iter().map(|&value| value as i64).sum::<i64>() &&
+            values.iter().all(|value| tree.contains(value) == reference.contains(value))
+    }
+
+    // A summary of the user's own should be there for the asking, and kept up to date.
+    #[test]
+    fn augmented_tree_keeps_users_summary() {
+        let mut tree: AugmentedTree<i32, Smallest> = AugmentedTree::new();
+        assert_eq!(None, tree.summary());
+        for &value in &[8, 3, 12, 5, 1, 9] {
+            tree.insert(value);
+        }
+        assert_eq!(Some(&Smallest(1)), tree.summary());
+
+        assert_eq!(Some(1), tree.remove(&1));
+        assert_eq!(Some(&Smallest(3)), tree.summary());
+        assert!(!tree.insert(3));
+        assert_eq!(5, tree.len());
+    }
+
+    // Every tree a failing case gets shrunk to should still be a valid tree, with just
+    // the one value missing.
+    #[quickcheck]
+    fn shrink_property(bt: BinaryTree<i32, (i8, i8)>) -> bool {
+        let values = in_order(&bt);
+        let shrunk: Vec<AvlTree<i32>> = bt.shrink().collect();
+        shrunk.len() == values.len() - if values.len() == 1 { 1 } else { 0 } &&
+            shrunk.iter().zip(0..).all(|(tree, i)| {
+                let mut expected = values.clone();
+                expected.remove(i);
+                in_order(tree) == expected && metadata_is_correct(tree) &&
+                    tree.iter().all(|node| node.metadata.0 - node.metadata.1 <= 1 && node.metadata.0 - node.metadata.1 >= -1)
+            })
+    }
+
+    // Inserting should report a duplicate exactly when the value was already there, and
+    // otherwise report growth exactly when the tree got taller, and either way say how
+    // many values are less than it.
+    #[quickcheck]
+    fn insert_outcome_property(bt: BinaryTree<i32, (i8, i8)>, value: i32) -> bool {
+        let mut bt = bt;
+        let present = bt.iter().any(|tree| tree.value == value);
+        let (before, size_before) = (taller(max(bt.metadata.0, bt.metadata.1)), bt.size);
+        let less = bt.iter().filter(|tree| tree.value < value).count();
+        let outcome = bt.insert::<Avl>(value);
+        let (after, size_after) = (taller(max(bt.metadata.0, bt.metadata.1)), bt.size);
+
+        match outcome {
+            InsertOutcome::Duplicate {value: duplicate, position} => {
+                present && duplicate == value && position == less && after == before && size_after == size_before
+            }
+            InsertOutcome::Inserted {grew, position} => {
+                !present && position == less && after == before + grew as i8 && size_after == size_before + 1
+            }
+        }
+    }
+
+    // Inserting a value that's already there shouldn't touch the tree at all: every
+    // node should still be in the same allocation, with the same value, metadata and
+    // size, as it was before.
+    #[quickcheck]
+    fn duplicate_insert_changes_nothing(values: Vec<i32>, pick: usize) -> bool {
+        if values.is_empty() {
+            return true
+        }
+        let mut tree = Tree::new();
+        for &value in &values {
+            tree.insert(value);
+        }
+        fn nodes(tree: &Tree<i32>) -> Vec<(usize, i32, (i8, i8), usize)> {
+            tree.root.iter().flat_map(|root| root.iter()).map(|node| (node as *const AvlTree<i32> as usize, node.value, node.metadata, node.size)).collect()
+        }
+
+        let before = nodes(&tree);
+        let inserted = tree.insert(values[pick % values.len()]);
+        !inserted && nodes(&tree) == before && tree.len() == before.len()
+    }
+
+    // Values that are ordered only by their key, so two values can be equal as far as
+    // the tree is concerned while still being distinguishable by their label.
+    #[allow(dead_code)]
+    #[derive(Clone, Debug)]
+    struct Keyed {
+        key: i32,
+        label: &'static str
+    }
+
+    impl PartialEq for Keyed {
+        fn eq(&self, other: &Keyed) -> bool {
+            self.key == other.key
+        }
+    }
+
+    impl Eq for Keyed {}
+
+    impl PartialOrd for Keyed {
+        fn partial_cmp(&self, other: &Keyed) -> Option<std::cmp::Ordering> {
+            Some(self.cmp(other))
+        }
+    }
+
+    impl Ord for Keyed {
+        fn cmp(&self, other: &Keyed) -> std::cmp::Ordering {
+            self.key.cmp(&other.key)
+        }
+    }
+
+    fn sorted_values(tree: &Tree<i32>) -> Vec<i32> {
+        tree.iter_sorted().cloned().collect()
+    }
+
+    // Splitting arbitrary values around an arbitrary pivot and joining the two halves back
+    // together should give us back a valid tree with all the values in it.
+    #[quickcheck]
+    fn join_property(values: Vec<i32>, pivot: i32) -> bool {
+        let (mut left, mut right) = (Tree::new(), Tree::new());
+        for &value in &values {
+            if value < pivot { left.insert(value); } else { right.insert(value); }
+        }
+
+        let mut expected = values.clone();
+        expected.sort();
+        expected.dedup();
+
+        let joined = Tree::join(left, right);
+        joined.len == expected.len() && sorted_values(&joined) == expected && joined.is_valid_avl()
+    }
+
+    // A cleared tree should be empty, and should go right back to working normally.
+    #[quickcheck]
+    fn clear_property(values: Vec<i32>, value: i32) -> bool {
+        let mut tree = Tree::new();
+        for &v in &values {
+            tree.insert(v);
+        }
+
+        tree.clear();
+        let cleared = tree.is_empty() && tree.root.is_none();
+
+        tree.insert(value);
+        cleared && tree.len() == 1 && tree.contains(&value)
+    }
+
+    #[test]
+    fn default_is_empty() {
+        let tree: Tree<i32> = Default::default();
+        assert!(tree.is_empty());
+        assert!(tree.root.is_none());
+    }
+
+    // Nothing about the tree needs values to be Copy, so owned values like Strings work too.
+    #[test]
+    fn string_values() {
+        let mut tree = Tree::new();
+        for word in "the quick brown fox jumps over the lazy dog".split(' ') {
+            tree.insert(word.to_string());
+        }
+
+        assert_eq!(8, tree.len());
+        assert!(tree.contains(&"fox".to_string()));
+        assert!(!tree.contains(&"cat".to_string()));
+        assert!(tree.contains("fox"));
+        assert_eq!(Some(&"lazy".to_string()), tree.get("lazy"));
+        assert_eq!(Some(&"over".to_string()), tree.floor("pig"));
+        assert_eq!(Some(&"quick".to_string()), tree.ceiling("pig"));
+        assert!(tree.remove("the"));
+        assert!(!tree.contains("the"));
+        assert_eq!(Ok(()), tree.check_invariants());
+
+        tree.retain(|word| word.len() > 3);
+        assert_eq!(vec!["brown", "jumps", "lazy", "over", "quick"], into_sorted(tree.root.take()));
+    }
+
+    // Removing arbitrary values, some of which are in the tree and some of which aren't,
+    // should leave a valid tree without them.
+    #[quickcheck]
+    fn remove_property(values: Vec<i32>, removals: Vec<i32>) -> bool {
+        let mut tree = Tree::new();
+        for &value in &values {
+            tree.insert(value);
+        }
+
+        let mut reference: std::collections::BTreeSet<i32> = values.into_iter().collect();
+        let removed_ok = removals.iter().all(|value| tree.remove(value) == reference.remove(value));
+        let expected: Vec<i32> = reference.into_iter().collect();
+        removed_ok && tree.len() == expected.len() && sorted_values(&tree) == expected && tree.is_valid_avl()
+    }
+
+    // Removing every kind of range should leave a valid tree with just the values outside
+    // of it.
+    #[quickcheck]
+    fn remove_range_property(values: Vec<i32>, start: i32, end: i32) -> bool {
+        fn check<R: RangeBounds<i32>+Clone>(values: &Vec<i32>, bounds: R) -> bool {
+            let mut tree = Tree::new();
+            for &value in values {
+                tree.insert(value);
+            }
+
+            let expected: Vec<i32> = tree.iter_sorted().cloned().filter(|value| !bounds.contains(value)).collect();
+            tree.remove_range(bounds);
+            tree.len() == expected.len() && sorted_values(&tree) == expected && tree.is_valid_avl()
+        }
+
+        check(&values, start..end) && check(&values, start..=end) && check(&values, ..end) && check(&values, start..) &&
+            check(&values, ..) && check(&values, (Bound::Excluded(start), Bound::Excluded(end)))
+    }
+
+    // Splitting off at any key should leave the values below it, and move the rest into
+    // the new tree, with both of them valid.
+    #[quickcheck]
+    fn split_off_property(values: Vec<i32>, key: i32) -> bool {
+        let mut tree = Tree::new();
+        for &value in &values {
+            tree.insert(value);
+        }
+        let original = sorted_values(&tree);
+
+        let split = tree.split_off(&key);
+        let (below, above): (Vec<i32>, Vec<i32>) = original.iter().partition(|&&value| value < key);
+        sorted_values(&tree) == below && sorted_values(&split) == above && tree.len() == below.len() &&
+            split.len() == above.len() && tree.is_valid_avl() && split.is_valid_avl()
+    }
+
+    // Keeping every kind of range should leave a valid tree with exactly what iterating
+    // over the range gave beforehand.
+    #[quickcheck]
+    fn retain_range_property(values: Vec<i32>, start: i32, end: i32) -> bool {
+        fn check<R: RangeBounds<i32>+Clone>(values: &Vec<i32>, bounds: R) -> bool {
+            let mut tree = Tree::new();
+            for &value in values {
+                tree.insert(value);
+            }
+
+            let expected: Vec<i32> = tree.range(bounds.clone()).cloned().collect();
+            tree.retain_range(bounds);
+            tree.len() == expected.len() && sorted_values(&tree) == expected && tree.is_valid_avl()
+        }
+
+        check(&values, start..end) && check(&values, start..=end) && check(&values, ..end) && check(&values, start..) &&
+            check(&values, ..) && check(&values, (Bound::Excluded(start), Bound::Excluded(end)))
+    }
+
+    // Draining part of the way should give the smallest values in order, and dropping the
+    // drain early should still leave the tree empty, with every value dropped.
+    #[quickcheck]
+    fn drain_property(values: Vec<i32>, taken: usize) -> bool {
+        let marker = std::rc::Rc::new(());
+        let mut tree = Tree::new();
+        for &value in &values {
+            tree.insert((value, marker.clone()));
+        }
+
+        let expected: Vec<i32> = tree.iter_sorted().map(|&(value, _)| value).take(taken).collect();
+        let drained: Vec<i32> = tree.drain().take(taken).map(|(value, _)| value).collect();
+
+        drained == expected && tree.is_empty() && tree.root.is_none() && std::rc::Rc::strong_count(&marker) == 1
+    }
+
+    // Getting or inserting should always hand back an equal value, and only grow the tree
+    // when the value is new.
+    #[quickcheck]
+    fn get_or_insert_property(values: Vec<i32>) -> bool {
+        let mut tree = Tree::new();
+        let mut reference = std::collections::BTreeSet::new();
+        values.into_iter().all(|value| {
+            let new = reference.insert(value);
+            let len = tree.len();
+            *tree.get_or_insert(value) == value && tree.len() == if new { len + 1 } else { len }
+        }) && tree.check_invariants().is_ok()
+    }
+
+    // Breaking a node and then changing the tree should panic right away in debug
+    // builds, saying which node is broken, even though i32s could be printed.
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "insert broke the tree: node #1 has size 1 but its subtree has 3 values")]
+    fn debug_checks_catch_corruption() {
+        let mut tree = Tree::new();
+        for value in 0..7 {
+            tree.insert(value);
+        }
+        // Inserting 7 only fixes up the nodes on its way down the right side.
+        tree.root.as_mut().unwrap().left.as_mut().unwrap().size = 1;
+        tree.insert(7);
+    }
+
+    // An Ord that says every value is less than every other one should be caught on
+    // the way down, before the value gets put anywhere.
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "inconsistent Ord")]
+    fn debug_checks_catch_broken_ord() {
+        #[derive(PartialEq, Eq, PartialOrd)]
+        struct Contrary(i32);
+
+        impl Ord for Contrary {
+            fn cmp(&self, other: &Contrary) -> std::cmp::Ordering {
+                if self.0 == other.0 { std::cmp::Ordering::Equal } else { std::cmp::Ordering::Less }
+            }
+        }
+
+        let mut tree = Tree::new();
+        tree.insert(Contrary(1));
+        tree.insert(Contrary(2));
+    }
+
+    // Corrupted metadata and sizes should be put right in place, counting each node
+    // that was wrong, without changing the shape of the tree.
+    #[test]
+    fn repair_fixes_metadata() {
+        let mut tree: Tree<i32> = (0..100).collect();
+        let original = tree.clone();
+        assert_eq!(0, tree.repair());
+
+        tree.root.as_mut().unwrap().metadata = (0, 0);
+        tree.root.as_mut().unwrap().left.as_mut().unwrap().size = 1;
+        tree.root.as_mut().unwrap().right.as_mut().unwrap().right.as_mut().unwrap().metadata.1 = 9;
+        tree.len = 3;
+        assert!(!tree.is_valid_avl());
+
+        assert_eq!(4, tree.repair());
+        assert!(tree.is_valid_avl());
+        assert!(tree.structurally_eq(&original));
+    }
+
+    // Metadata at the very ends of what an i8 can hold should just make the tree
+    // invalid, without any arithmetic on it overflowing and panicking.
+    #[test]
+    fn is_valid_avl_survives_extreme_metadata() {
+        let mut tree: Tree<i32> = (0..100).collect();
+        tree.root.as_mut().unwrap().metadata = (i8::MAX, i8::MIN);
+        assert!(!tree.is_valid_avl());
+
+        let mut tree: Tree<i32> = (0..100).collect();
+        tree.root.as_mut().unwrap().left.as_mut().unwrap().metadata = (i8::MAX, 0);
+        assert!(!tree.is_valid_avl());
+    }
+
+    // A tree that's out of balance or out of order can't be fixed node by node, so it
+    // should be rebuilt into a valid tree of the same values.
+    #[test]
+    fn repair_rebuilds_broken_shapes() {
+        let mut chain = AvlTree::leaf(1);
+        chain.right = Some(Box::new(AvlTree::leaf(2)));
+        chain.right.as_mut().unwrap().right = Some(Box::new(AvlTree::leaf(3)));
+        let mut tree = Tree {root: Some(Box::new(chain)), len: 3, #[cfg(feature = "rotation-count")] rotations: 0, policy: PhantomData};
+        assert_eq!(3, tree.repair());
+        assert!(tree.is_valid_avl());
+        assert_eq!(vec![1, 2, 3], sorted_values(&tree));
+
+        let mut swapped: Tree<i32> = (0..10).collect();
+        {
+            let root = swapped.root.as_mut().unwrap();
+            mem::swap(&mut root.left.as_mut().unwrap().value, &mut root.right.as_mut().unwrap().value);
+        }
+        assert_eq!(1, swapped.repair());
+        assert!(swapped.is_valid_avl());
+        assert_eq!((0..10).collect::<Vec<_>>(), sorted_values(&swapped));
+    }
+
+    // Ascending inserts turn a tree that doesn't rebalance into a list, while the AVL
+    // tree stays within its bound. Either way the values are all there, in order.
+    #[test]
+    fn no_rebalance_policy_grows_tall() {
+        let mut unbalanced = Tree::<i32, NoRebalance>::with_policy();
+        let mut balanced = Tree::new();
+        for value in 0..1_000 {
+            assert!(unbalanced.insert(value));
+            balanced.insert(value);
+        }
+        assert!(!unbalanced.insert(500));
+
+        assert_eq!(1_000, unbalanced.height());
+        assert!(balanced.height() <= height_bound(1_000));
+        assert!(unbalanced.check_invariants().is_ok());
+        assert!(unbalanced.contains(&999) && !unbalanced.contains(&1_000));
+        assert!(unbalanced.iter_sorted().eq(balanced.iter_sorted()));
+    }
+
+    // The bound should be the height of the sparsest possible AVL trees, and no tree
+    // built any way at all should be taller.
+    #[test]
+    fn height_bound_is_tight() {
+        let fewest = [0, 1, 2, 4, 7, 12, 20, 33, 54];
+        for (height, &len) in fewest.iter().enumerate() {
+            assert_eq!(height, height_bound(len));
+            if len > 0 {
+                assert_eq!(height - 1, height_bound(len - 1));
+            }
+        }
+        assert!(height_bound(usize::max_value()) < 100);
+
+        let mut tree = Tree::new();
+        for value in 0..1000 {
+            tree.insert(value);
+            tree.assert_height_bound();
+        }
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "a tree of 2 values has height 3, but can be at most 2 tall")]
+    fn height_bound_catches_lopsided_trees() {
+        let mut tree = Tree::new();
+        tree.insert(1);
+        tree.insert(2);
+        tree.root.as_mut().unwrap().metadata = (0, 2);
+        tree.assert_height_bound();
+    }
+
+    #[test]
+    #[should_panic(expected = "maximum supported height")]
+    fn height_overflow_panics() {
+        let mut tree = AvlTree::leaf(0);
+        tree.metadata = (127, 126);
+        height(&Some(Box::new(tree)));
+    }
+
+    // When there's already an equal value, that's the one we get back.
+    #[test]
+    fn get_or_insert_interns() {
+        let mut tree = Tree::new();
+        assert_eq!("first", tree.get_or_insert(Keyed {key: 1, label: "first"}).label);
+        assert_eq!("first", tree.get_or_insert(Keyed {key: 1, label: "second"}).label);
+        assert_eq!(1, tree.len());
+    }
+
+    // The value should only be made when it's missing.
+    #[test]
+    fn get_or_insert_with_makes_missing_values() {
+        let mut tree: Tree<String> = ["apple", "pear"].iter().map(|name| name.to_string()).collect();
+        let mut made = Vec::new();
+        for &name in &["pear", "cherry", "apple", "cherry"] {
+            let value = tree.get_or_insert_with(name, |name: &str| {
+                made.push(name.to_string());
+                name.to_string()
+            });
+            assert_eq!(name, value);
+        }
+
+        assert_eq!(vec!["cherry"], made);
+        assert_eq!(vec!["apple", "cherry", "pear"], tree.iter().collect::<Vec<&String>>());
+        assert!(tree.check_invariants().is_ok());
+    }
+
+    // Replacing should swap in the new value and hand back the old one, without
+    // changing anything else about the tree.
+    #[test]
+    fn replace_swaps_equal_values() {
+        let mut tree = Tree::new();
+        for key in 0..10 {
+            tree.insert(Keyed {key: key, label: "old"});
+        }
+        let drawn = format!("{:?}", tree);
+
+        assert_eq!(Some("old"), tree.replace(Keyed {key: 3, label: "new"}).map(|old| old.label));
+        assert_eq!(drawn.replace("key: 3, label: \"old\"", "key: 3, label: \"new\""), format!("{:?}", tree));
+        assert_eq!(None, tree.replace(Keyed {key: 10, label: "new"}).map(|old| old.label));
+
+        let labels: Vec<&str> = tree.iter_sorted().map(|value| value.label).collect();
+        assert_eq!(vec!["old", "old", "old", "new", "old", "old", "old", "old", "old", "old", "new"], labels);
+        assert_eq!(11, tree.len());
+        assert!(tree.check_invariants().is_ok());
+    }
+
+    #[test]
+    fn insert_and_get_returns_the_stored_value() {
+        let mut tree = Tree::new();
+        for key in 0..10 {
+            tree.insert(Keyed {key: key * 2, label: "first"});
+        }
+        assert_eq!("first", tree.insert_and_get(Keyed {key: 4, label: "second"}).label);
+        assert_eq!("second", tree.insert_and_get(Keyed {key: 5, label: "second"}).label);
+        assert_eq!(11, tree.len());
+    }
+
+    // Appending should leave other empty and this tree holding every value from both,
+    // whether or not their values overlap. Shifting the second set of values by an
+    // arbitrary amount makes the disjoint cases, in both directions, come up often.
+    #[quickcheck]
+    fn append_property(values: Vec<i32>, others: Vec<i32>, shift: i32) -> bool {
+        let others: Vec<i32> = others.iter().map(|value| value.wrapping_add(shift)).collect();
+        let (mut tree, mut other) = (Tree::new(), Tree::new());
+        for &value in &values {
+            tree.insert(value);
+        }
+        for &value in &others {
+            other.insert(value);
+        }
+
+        let mut expected: Vec<i32> = values.iter().chain(others.iter()).cloned().collect();
+        expected.sort();
+        expected.dedup();
+
+        tree.append(&mut other);
+        other.is_empty() && other.first().is_none() && other.check_invariants().is_ok() &&
+            tree.len() == expected.len() && sorted_values(&tree) == expected && tree.check_invariants().is_ok()
+    }
+
+    // When both trees have an equal value, the one that was already there wins.
+    #[test]
+    fn append_keeps_existing_values() {
+        let (mut tree, mut other) = (Tree::new(), Tree::new());
+        tree.insert(Keyed {key: 1, label: "mine"});
+        tree.insert(Keyed {key: 3, label: "mine"});
+        other.insert(Keyed {key: 1, label: "theirs"});
+        other.insert(Keyed {key: 2, label: "theirs"});
+
+        tree.append(&mut other);
+        let labels: Vec<&str> = tree.iter_sorted().map(|value| value.label).collect();
+        assert_eq!(vec!["mine", "theirs", "mine"], labels);
+        assert!(other.is_empty());
+    }
+
+    // Inserting 1 through 7 in order rotates its way to a perfectly balanced tree with
+    // 4 at the root, so the depths are easy to know ahead of time.
+    #[test]
+    fn iter_with_depth_reports_depths() {
+        let mut tree = Tree::new();
+        for value in 1..8 {
+            tree.insert(value);
+        }
+
+        let depths: Vec<(i32, usize)> = tree.iter_with_depth().map(|(&value, depth)| (value, depth)).collect();
+        assert_eq!(vec![(1, 2), (2, 1), (3, 2), (4, 0), (5, 2), (6, 1), (7, 2)], depths);
+        assert_eq!(0, Tree::<i32>::new().iter_with_depth().count());
+    }
+
+    // A capped tree should always hold the smallest (or largest) values it's been given,
+    // up to the cap, and hand back the ones that don't make it. A value can come back
+    // more than once, if it's given again after it was evicted.
+    #[quickcheck]
+    fn insert_capped_property(values: Vec<i32>, cap: u8, largest: bool) -> bool {
+        let (cap, evict) = (cap as usize % 20, if largest { Evict::Largest } else { Evict::Smallest });
+        let mut tree = Tree::new();
+        let mut evicted = Vec::new();
+        for &value in &values {
+            evicted.extend(tree.insert_capped(value, cap, evict));
+            if tree.len() > cap {
+                return false
+            }
+        }
+
+        let mut distinct: Vec<i32> = values.clone();
+        distinct.sort();
+        distinct.dedup();
+        let mut kept = distinct.clone();
+        if largest {
+            kept.truncate(cap);
+        } else {
+            kept.drain(..kept.len().saturating_sub(cap));
+        }
+        evicted.sort();
+        evicted.dedup();
+        let mut all = evicted.clone();
+        all.extend(kept.iter().cloned());
+        all.sort();
+        sorted_values(&tree) == kept && all == distinct
+    }
+
+    #[test]
+    fn insert_capped_hands_back_its_own_value() {
+        let mut tree: Tree<i32> = (0..5).collect();
+        assert_eq!(Some(10), tree.insert_capped(10, 5, Evict::Largest));
+        assert_eq!(Some(4), tree.insert_capped(-1, 5, Evict::Largest));
+        assert_eq!(Some(-5), tree.insert_capped(-5, 5, Evict::Smallest));
+        assert_eq!(None, tree.insert_capped(2, 5, Evict::Smallest));
+        assert_eq!(Some(-1), tree.insert_capped(10, 5, Evict::Smallest));
+        assert_eq!(vec![0, 1, 2, 3, 10].into_iter().collect::<Tree<i32>>(), tree);
+        assert_eq!(Some(7), Tree::new().insert_capped(7, 0, Evict::Largest));
+
+        // Lowering the cap evicts one value per insert, not everything over it.
+        assert_eq!(Some(10), tree.insert_capped(-1, 2, Evict::Largest));
+        assert_eq!(vec![-1, 0, 1, 2, 3], sorted_values(&tree));
+    }
+
+    // However a tree gets rotated about by a run of inserts and removes, every node's
+    // size and heights should match what's actually below it, not just the root's.
+    #[quickcheck]
+    fn sizes_and_heights_agree_property(ops: Vec<(bool, u8)>) -> bool {
+        fn agrees(tree: &Option<Box<AvlTree<u8>>>) -> Option<(usize, i8)> {
+            match *tree {
+                Some(ref node) => {
+                    let (left, right) = (agrees(&node.left)?, agrees(&node.right)?);
+                    if node.size == 1 + left.0 + right.0 && node.metadata == (left.1, right.1) {
+                        Some((node.size, 1 + max(left.1, right.1)))
+                    } else {
+                        None
+                    }
+                }
+                None => Some((0, 0))
+            }
+        }
+
+        let mut tree = Tree::new();
+        ops.iter().all(|&(insert, value)| {
+            let value = value % 32;
+            if insert { tree.insert(value); } else { tree.remove(&value); }
+            agrees(&tree.root).map(|(size, _)| size) == Some(tree.len())
+        })
+    }
+
+    // Popping from either end should give the values in order, and leave a valid tree
+    // every time.
+    #[quickcheck]
+    fn pop_first_last_property(values: Vec<i32>, from_back: bool) -> bool {
+        let mut tree: Tree<i32> = values.iter().cloned().collect();
+        let mut expected = sorted_values(&tree);
+        if from_back {
+            expected.reverse();
+        }
+        let mut popped = Vec::new();
+        while let Some(value) = if from_back { tree.pop_last() } else { tree.pop_first() } {
+            popped.push(value);
+            if tree.check_invariants().is_err() || tree.len() != expected.len() - popped.len() {
+                return false
+            }
+        }
+        popped == expected && tree.is_empty()
+    }
+
+    // Building from sorted values and inserting in the right order give the same
+    // perfect tree, while a tree of the same values with a different shape is only
+    // equal as a set.
+    #[test]
+    fn structurally_eq_compares_shape() {
+        let mut inserted = Tree::new();
+        for &value in &[4, 2, 6, 1, 3, 5, 7] {
+            inserted.insert(value);
+        }
+        assert!(Tree::from_sorted((1..8).collect()).structurally_eq(&inserted));
+
+        let mut leaning = Tree::new();
+        for &value in &[2, 1, 3, 4] {
+            leaning.insert(value);
+        }
+        let built = Tree::from_sorted(vec![1, 2, 3, 4]);
+        assert!(built == leaning);
+        assert!(!built.structurally_eq(&leaning));
+        assert!(Tree::<i32>::new().structurally_eq(&Tree::new()));
+        assert!(!built.structurally_eq(&Tree::new()));
+    }
+
+    // Folding should visit the values in the same order iterating does, and a fold that
+    // breaks should stop right there.
+    #[quickcheck]
+    fn fold_in_order_property(values: Vec<i32>, stop: i32) -> bool {
+        let tree: Tree<i32> = values.iter().cloned().collect();
+        let folded = tree.fold_in_order(Vec::new(), |mut seen, &value| {
+            seen.push(value);
+            seen
+        });
+
+        let mut visited = 0;
+        let found = tree.try_fold_in_order(0, |count, &value| {
+            visited += 1;
+            if value > stop { ControlFlow::Break(value) } else { ControlFlow::Continue(count + 1) }
+        });
+        let below = folded.iter().filter(|&&value| value <= stop).count();
+        let expected = match folded.iter().find(|&&value| value > stop) {
+            Some(&value) => ControlFlow::Break(value),
+            None => ControlFlow::Continue(below)
+        };
+
+        folded == tree.iter_sorted().fold(Vec::new(), |mut seen, &value| { seen.push(value); seen }) &&
+            found == expected && visited == tree.len().min(below + 1)
+    }
+
+    // In a perfect tree of 1 to 7, each node's subtree is the values around it.
+    #[test]
+    fn subtree_iter_covers_the_subtree() {
+        let mut tree = Tree::new();
+        for value in 1..8 {
+            tree.insert(value);
+        }
+
+        let subtree = |value| tree.subtree_iter(&value).map(|iter| iter.cloned().collect::<Vec<i32>>());
+        assert_eq!(Some((1..8).collect()), subtree(4));
+        assert_eq!(Some(vec![1, 2, 3]), subtree(2));
+        assert_eq!(Some(vec![5, 6, 7]), subtree(6));
+        assert_eq!(Some(vec![5]), subtree(5));
+        assert_eq!(None, subtree(8));
+        assert_eq!(3, tree.subtree_iter(&6).unwrap().len());
+        assert!(Tree::<i32>::new().subtree_iter(&1).is_none());
+    }
+
+    // Bulk inserting should end up with the same values as inserting them one at a time,
+    // whether the batch is big enough to rebuild the tree or small enough not to.
+    #[quickcheck]
+    fn bulk_insert_property(values: Vec<i32>, items: Vec<i32>, small: bool) -> bool {
+        let items: Vec<i32> = if small { items.into_iter().take(values.len() / 10).collect() } else { items };
+        let (mut tree, mut reference) = (Tree::new(), Tree::new());
+        for &value in &values {
+            tree.insert(value);
+            reference.insert(value);
+        }
+        for &item in &items {
+            reference.insert(item);
+        }
+
+        tree.bulk_insert(items);
+        tree.len() == reference.len() && sorted_values(&tree) == sorted_values(&reference) && tree.check_invariants().is_ok()
+    }
+
+    // Just like insert, the value that was there first is the one that stays.
+    #[test]
+    fn bulk_insert_keeps_existing_values() {
+        let mut tree = Tree::new();
+        tree.insert(Keyed {key: 1, label: "existing"});
+        tree.bulk_insert(vec![Keyed {key: 2, label: "first"}, Keyed {key: 1, label: "new"}, Keyed {key: 2, label: "second"}]);
+
+        let labels: Vec<&str> = tree.iter_sorted().map(|value| value.label).collect();
+        assert_eq!(vec!["existing", "first"], labels);
+    }
+
+    // Extending from an unsorted slice should give the same values as inserting them one
+    // at a time, both when the slice is small next to the tree and when it's not.
+    #[quickcheck]
+    fn extend_from_slice_property(values: Vec<i32>, items: Vec<i32>, small: bool) -> bool {
+        let items: Vec<i32> = if small { items.into_iter().take(values.len() / 10).collect() } else { items };
+        let mut tree: Tree<i32> = values.iter().cloned().collect();
+        let mut reference = tree.clone();
+        for &item in &items {
+            reference.insert(item);
+        }
+
+        tree.extend_from_slice(&items);
+        tree.len() == reference.len() && sorted_values(&tree) == sorted_values(&reference) && tree.check_invariants().is_ok()
+    }
+
+    #[test]
+    fn extend_from_slice_unsorted() {
+        let mut tree: Tree<i32> = (0..100).map(|value| value * 2).collect();
+        tree.extend_from_slice(&[7, 3, 3, 151]);
+        assert_eq!(103, tree.len());
+        tree.extend_from_slice(&(0..300).rev().collect::<Vec<i32>>());
+        assert_eq!((0..300).collect::<Vec<i32>>(), sorted_values(&tree));
+        assert!(tree.check_invariants().is_ok());
+    }
+
+    // Merging a sorted slice should give the same values as inserting them one at a time.
+    #[quickcheck]
+    fn merge_sorted_slice_property(values: Vec<i32>, items: Vec<i32>) -> bool {
+        let mut tree: Tree<i32> = values.iter().cloned().collect();
+        let mut reference = tree.clone();
+        let mut items = items;
+        items.sort();
+        items.dedup();
+        for &item in &items {
+            reference.insert(item);
+        }
+
+        tree.merge_sorted_slice(&items);
+        tree.len() == reference.len() && sorted_values(&tree) == sorted_values(&reference) && tree.check_invariants().is_ok()
+    }
+
+    // Removing a sorted slice should leave what removing each value would, and count
+    // only the values that were there.
+    #[quickcheck]
+    fn remove_sorted_slice_property(values: Vec<i32>, removing: Vec<i32>) -> bool {
+        let mut tree: Tree<i32> = values.iter().cloned().collect();
+        let mut reference = tree.clone();
+        let mut removing = removing;
+        removing.extend(values.iter().step_by(2));
+        removing.sort();
+        removing.dedup();
+        let present = removing.iter().filter(|&&value| reference.remove(&value)).count();
+
+        tree.remove_sorted_slice(&removing) == present && sorted_values(&tree) == sorted_values(&reference) &&
+            tree.len() == reference.len() && tree.check_invariants().is_ok()
+    }
+
+    #[test]
+    fn merge_sorted_slice_keeps_existing_values() {
+        let mut tree = Tree::new();
+        tree.insert(Keyed {key: 1, label: "existing"});
+        tree.merge_sorted_slice(&[Keyed {key: 0, label: "new"}, Keyed {key: 1, label: "new"}]);
+
+        let labels: Vec<&str> = tree.iter_sorted().map(|value| value.label).collect();
+        assert_eq!(vec!["new", "existing"], labels);
+    }
+
+    // Trees should compare just like the sorted, deduplicated Vecs of their values do,
+    // whatever order the values went in.
+    #[quickcheck]
+    fn comparison_property(a: Vec<i32>, b: Vec<i32>) -> bool {
+        let (mut first, mut second) = (Tree::new(), Tree::new());
+        for &value in &a {
+            first.insert(value);
+        }
+        for &value in b.iter().rev() {
+            second.insert(value);
+        }
+
+        let (a, b) = (sorted_values(&first), sorted_values(&second));
+        let shuffled = from_shuffled(a.clone(), a.len() as u64);
+        first.cmp(&second) == a.cmp(&b) && (first == second) == (a == b) && first.partial_cmp(&second) == Some(a.cmp(&b)) &&
+            shuffled == first && shuffled.cmp(&first) == Ordering::Equal
+    }
+
+    // Building in parallel should build exactly the tree building in order does, for
+    // trees big enough to go parallel and ones that aren't.
+    #[test]
+    #[cfg(feature = "rayon")]
+    fn from_sorted_par_matches_from_sorted() {
+        for &n in &[0, 10, PARALLEL_THRESHOLD * 3 + 7] {
+            let parallel = Tree::from_sorted_par((0..n).collect());
+            let sequential = Tree::from_sorted((0..n).collect());
+            assert_eq!(format!("{:?}", sequential), format!("{:?}", parallel));
+            assert!(parallel.is_valid_avl());
+        }
+    }
+
+    // Building from an iterator should give a valid tree of exactly the values it had,
+    // whether the hint was right, too big or too small.
+    #[quickcheck]
+    fn from_sorted_iter_property(values: Vec<i32>, hint: usize) -> bool {
+        let mut values = values;
+        values.sort();
+        values.dedup();
+
+        [values.len(), hint % (values.len() * 2 + 1), 0].iter().all(|&hint| {
+            let tree = Tree::from_sorted_iter(values.iter().cloned(), hint);
+            sorted_values(&tree) == values && tree.len() == values.len() && tree.is_valid_avl()
+        })
+    }
+
+    // An iterator that runs out and then starts again shouldn't get its later values
+    // into the tree, since they'd land wherever building had got to.
+    #[test]
+    fn from_sorted_iter_stops_at_the_first_none() {
+        struct Hiccup {
+            next: i32
+        }
+
+        impl Iterator for Hiccup {
+            type Item = i32;
+
+            fn next(&mut self) -> Option<i32> {
+                self.next += 1;
+                if self.next == 4 || self.next > 8 { None } else { Some(self.next) }
+            }
+        }
+
+        let tree = Tree::from_sorted_iter(Hiccup {next: 0}, 10);
+        assert_eq!(vec![1, 2, 3], sorted_values(&tree));
+        assert!(tree.is_valid_avl());
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "values must be strictly ascending")]
+    fn from_sorted_iter_checks_order() {
+        Tree::from_sorted_iter(vec![1, 2, 4, 3, 5].into_iter(), 5);
+    }
+
+    // Cloning should copy the shape exactly, and cloning balanced should give the same
+    // values in a tree as short as from_sorted would make.
+    #[quickcheck]
+    fn clone_property(values: Vec<i32>) -> bool {
+        let mut tree = Tree::new();
+        for &value in &values {
+            tree.insert(value);
+        }
+
+        let (clone, balanced) = (tree.clone(), tree.clone_balanced());
+        let rebuilt = Tree::from_sorted(sorted_values(&tree));
+        format!("{:?}", clone) == format!("{:?}", tree) && format!("{:?}", balanced) == format!("{:?}", rebuilt) &&
+            clone == tree && balanced == tree && balanced.is_valid_avl()
+    }
+
+    // Mapping with an increasing function should give the same tree as mapping the
+    // values and building a tree from them.
+    #[quickcheck]
+    fn map_monotonic_property(values: Vec<i32>) -> bool {
+        let mut tree = Tree::new();
+        for &value in &values {
+            tree.insert(value);
+        }
+
+        let mapped = tree.map_monotonic(|&value| (value as i64) * 3 - 7);
+        let expected: Vec<i64> = tree.iter_sorted().map(|&value| (value as i64) * 3 - 7).collect();
+        mapped.iter_sorted().cloned().collect::<Vec<_>>() == expected && mapped.len() == tree.len() && mapped.is_valid_avl()
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "values must be strictly ascending")]
+    fn map_monotonic_checks_order() {
+        let tree: Tree<i32> = (-5..5).collect();
+        tree.map_monotonic(|value| value * value);
+    }
+
+    // Collecting should give the same tree as inserting the values one at a time would,
+    // down to which of several equal values is kept.
+    #[quickcheck]
+    fn from_iter_property(values: Vec<i32>) -> bool {
+        let tree: Tree<i32> = values.iter().cloned().collect();
+        let mut expected = values.clone();
+        expected.sort();
+        expected.dedup();
+        sorted_values(&tree) == expected && tree.len() == expected.len() && tree.check_invariants().is_ok()
+    }
+
+    #[test]
+    fn from_iter_keeps_first_seen() {
+        let tree: Tree<Keyed> = vec![Keyed {key: 2, label: "first"}, Keyed {key: 1, label: "only"}, Keyed {key: 2, label: "second"}].into_iter().collect();
+        let labels: Vec<&str> = tree.iter_sorted().map(|value| value.label).collect();
+        assert_eq!(vec!["only", "first"], labels);
+    }
+
+    // However the tree got built, no node in it should ever be more than one level out
+    // of balance, and the empty tree isn't out of balance at all.
+    #[quickcheck]
+    fn imbalance_property(values: Vec<i32>, removals: Vec<i32>) -> bool {
+        let mut tree = Tree::new();
+        let empty_ok = tree.balance_factor() == 0 && tree.max_imbalance() == 0;
+        for &value in &values {
+            tree.insert(value);
+        }
+        for value in &removals {
+            tree.remove(value);
+        }
+        empty_ok && tree.balance_factor().abs() <= tree.max_imbalance() && tree.max_imbalance() <= 1
+    }
+
+    // Corrupting the metadata of a node deep in the tree should show up in max_imbalance
+    // but not in balance_factor, which only looks at the root.
+    #[test]
+    fn max_imbalance_finds_deep_nodes() {
+        let mut tree = Tree::new();
+        for value in 1..16 {
+            tree.insert(value);
+        }
+        assert_eq!((0, 0), (tree.balance_factor(), tree.max_imbalance()));
+
+        tree.root.as_mut().unwrap().left.as_mut().unwrap().left.as_mut().unwrap().metadata = (3, 0);
+        assert_eq!((0, 3), (tree.balance_factor(), tree.max_imbalance()));
+    }
+
+    // A cursor should start out in between the values below the one it was made at and
+    // the rest, and moving it to either end and back should visit every value in order.
+    #[quickcheck]
+    fn cursor_property(values: Vec<i32>, value: i32) -> bool {
+        let mut tree = Tree::new();
+        for &value in &values {
+            tree.insert(value);
+        }
+        let sorted = sorted_values(&tree);
+        let (below, rest): (Vec<i32>, Vec<i32>) = sorted.iter().partition(|&&other| other < value);
+
+        let mut cursor = tree.cursor_at(&value);
+        let start_ok = cursor.peek_prev() == below.last() && cursor.peek_next() == rest.first();
+
+        let forwards: Vec<i32> = std::iter::from_fn(|| cursor.move_next()).cloned().collect();
+        let end_ok = cursor.peek_next().is_none() && cursor.peek_prev() == sorted.last() && cursor.move_next().is_none();
+
+        let mut backwards: Vec<i32> = std::iter::from_fn(|| cursor.move_prev()).cloned().collect();
+        backwards.reverse();
+        let start_ok = start_ok && cursor.peek_prev().is_none() && cursor.peek_next() == sorted.first() && cursor.move_prev().is_none();
+
+        start_ok && end_ok && forwards == rest && backwards == sorted
+    }
+
+    // The lower bound should be just before the first value at least as big as the one
+    // given, and the upper bound just before the first value bigger than it.
+    #[quickcheck]
+    fn bounds_property(values: Vec<i32>, value: i32) -> bool {
+        let mut tree = Tree::new();
+        for &value in &values {
+            tree.insert(value);
+        }
+        let sorted = sorted_values(&tree);
+        let (lower, upper) = (tree.lower_bound(&value), tree.upper_bound(&value));
+
+        lower.peek_next() == sorted.iter().find(|&&other| other >= value) &&
+            lower.peek_prev() == sorted.iter().rev().find(|&&other| other < value) &&
+            upper.peek_next() == sorted.iter().find(|&&other| other > value) &&
+            upper.peek_prev() == sorted.iter().rev().find(|&&other| other <= value)
+    }
+
+    #[test]
+    fn bounds_at_the_ends() {
+        let mut tree = Tree::new();
+        for value in 1..10 {
+            tree.insert(value);
+        }
+        assert_eq!((None, Some(&1)), (tree.lower_bound(&-5).peek_prev(), tree.lower_bound(&-5).peek_next()));
+        assert_eq!((Some(&9), None), (tree.upper_bound(&9).peek_prev(), tree.upper_bound(&9).peek_next()));
+        assert_eq!((Some(&4), Some(&5)), (tree.upper_bound(&4).peek_prev(), tree.upper_bound(&4).peek_next()));
+        assert_eq!(Some(&9), tree.lower_bound(&100).move_prev());
+    }
+
+    // Stepping back and forth should keep handing back the same neighbours.
+    #[test]
+    fn cursor_moves_both_ways() {
+        let mut tree = Tree::new();
+        for value in 0..20 {
+            tree.insert(value * 2);
+        }
+
+        let mut cursor = tree.cursor_at(&15);
+        assert_eq!((Some(&14), Some(&16)), (cursor.peek_prev(), cursor.peek_next()));
+        assert_eq!(Some(&16), cursor.move_next());
+        assert_eq!(Some(&16), cursor.move_prev());
+        assert_eq!(Some(&14), cursor.move_prev());
+        assert_eq!((Some(&12), Some(&14)), (cursor.peek_prev(), cursor.peek_next()));
+    }
+
+    // Strictly ascending values should build a valid tree, and anything else should get
+    // the values handed back, with the index of the first one out of order.
+    #[quickcheck]
+    fn try_from_sorted_property(values: Vec<i32>) -> bool {
+        let mut sorted = values.clone();
+        sorted.sort();
+        sorted.dedup();
+
+        let sorted_ok = match Tree::try_from_sorted(sorted.clone()) {
+            Ok(tree) => sorted_values(&tree) == sorted && tree.check_invariants().is_ok(),
+            Err(_) => false
+        };
+        let unsorted_ok = match Tree::try_from_sorted(values.clone()) {
+            Ok(tree) => values == sorted && sorted_values(&tree) == sorted,
+            Err((index, returned)) => {
+                returned == values && values[index - 1] >= values[index] &&
+                    values[..index].windows(2).all(|pair| pair[0] < pair[1])
+            }
+        };
+        sorted_ok && unsorted_ok
+    }
+
+    // The batch lookups should agree with checking each value in a BTreeSet.
+    #[quickcheck]
+    fn batch_contains_property(values: Vec<i32>, queries: Vec<i32>) -> bool {
+        let mut tree = Tree::new();
+        for &value in &values {
+            tree.insert(value);
+        }
+        let reference: std::collections::BTreeSet<i32> = values.iter().cloned().collect();
+
+        let missing: Vec<&i32> = queries.iter().filter(|query| !reference.contains(query)).collect();
+        tree.missing(&queries) == missing &&
+            tree.contains_all(&queries) == missing.is_empty() &&
+            tree.contains_any(&queries) == queries.iter().any(|query| reference.contains(query))
+    }
+
+    // A snapshot should hold the values the tree had when it was taken, however the tree
+    // changes afterwards, even if it's emptied out entirely.
+    #[quickcheck]
+    fn snapshot_property(values: Vec<i32>, later: Vec<i32>) -> bool {
+        let mut tree = Tree::new();
+        for &value in &values {
+            tree.insert(value);
+        }
+        let expected = sorted_values(&tree);
+
+        let snapshot = tree.snapshot();
+        let mut iter = snapshot.iter_sorted();
+        let first = iter.next().cloned();
+        for &value in &later {
+            tree.insert(value);
+            tree.remove(&value.wrapping_add(1));
+        }
+        tree.clear();
+
+        let rest: Vec<i32> = iter.cloned().collect();
+        snapshot.len() == expected.len() && first.iter().chain(rest.iter()).cloned().collect::<Vec<_>>() == expected
+    }
+
+    // For a threshold predicate, the partition point is the first value at or after the
+    // threshold.
+    #[quickcheck]
+    fn partition_point_property(values: Vec<i32>, threshold: i32) -> bool {
+        let mut tree = Tree::new();
+        for &value in &values {
+            tree.insert(value);
+        }
+        let expected = sorted_values(&tree).into_iter().find(|&value| value >= threshold);
+        tree.partition_point(|&value| value < threshold).cloned() == expected
+    }
+
+    #[test]
+    fn partition_point_finds_thresholds() {
+        let mut tree = Tree::new();
+        for value in 0..50 {
+            tree.insert(value * 2);
+        }
+        assert_eq!(Some(&0), tree.partition_point(|&value| value < -5));
+        assert_eq!(Some(&22), tree.partition_point(|&value| value < 21));
+        assert_eq!(Some(&22), tree.partition_point(|&value| value < 22));
+        assert_eq!(None, tree.partition_point(|&value| value < 1000));
+        assert_eq!(None, Tree::<i32>::new().partition_point(|_| false));
+    }
+
+    // Merging should give every value from any of the trees, once each, in order.
+    #[quickcheck]
+    fn merge_sorted_property(layers: Vec<Vec<i32>>) -> bool {
+        let trees: Vec<Tree<i32>> = layers.iter().map(|values| {
+            let mut tree = Tree::new();
+            for &value in values {
+                tree.insert(value);
+            }
+            tree
+        }).collect();
+        let refs: Vec<&Tree<i32>> = trees.iter().collect();
+
+        let expected: std::collections::BTreeSet<i32> = layers.iter().flat_map(|values| values.iter().cloned()).collect();
+        merge_sorted(&refs).cloned().collect::<Vec<_>>() == expected.into_iter().collect::<Vec<_>>()
+    }
+
+    // Of equal values, the one from the first tree it's in is the one we get.
+    #[test]
+    fn merge_sorted_prefers_earlier_trees() {
+        let (mut newer, mut older) = (Tree::new(), Tree::new());
+        newer.insert(Keyed {key: 2, label: "newer"});
+        older.insert(Keyed {key: 1, label: "older"});
+        older.insert(Keyed {key: 2, label: "older"});
+
+        let labels: Vec<&str> = merge_sorted(&[&newer, &older]).map(|value| value.label).collect();
+        assert_eq!(vec!["older", "newer"], labels);
+    }
+
+    // The values drained and the values left behind should split the original values
+    // between them by the predicate, both in order.
+    #[quickcheck]
+    fn drain_filter_property(values: Vec<i32>, divisor: i32) -> bool {
+        let divisor = divisor.wrapping_abs() % 5 + 1;
+        let mut tree = Tree::new();
+        for &value in &values {
+            tree.insert(value);
+        }
+        let (expected_drained, expected_kept): (Vec<i32>, Vec<i32>) = sorted_values(&tree).into_iter().partition(|value| value % divisor == 0);
+
+        let drained: Vec<i32> = tree.drain_filter(|value| value % divisor == 0).collect();
+        drained == expected_drained && sorted_values(&tree) == expected_kept &&
+            tree.len() == expected_kept.len() && tree.check_invariants().is_ok()
+    }
+
+    // Every node is a separate allocation, so the estimate should grow by one node's
+    // worth per value, and shrink back when they're removed.
+    #[test]
+    fn memory_usage_counts_nodes() {
+        let mut tree = Tree::new();
+        let empty = tree.memory_usage();
+        for value in 0..100u64 {
+            tree.insert(value);
+        }
+        assert_eq!(empty + 100 * mem::size_of::<AvlTree<u64>>(), tree.memory_usage());
+        tree.remove_range(10..);
+        assert_eq!(empty + 10 * mem::size_of::<AvlTree<u64>>(), tree.memory_usage());
+    }
+
+    // The count of new values should be how much the tree grew, and with the duplicates
+    // should add up to the whole batch.
+    #[quickcheck]
+    fn insert_many_property(values: Vec<i32>, batch: Vec<i32>) -> bool {
+        let mut tree: Tree<i32> = values.iter().cloned().collect();
+        let mut reference: std::collections::BTreeSet<i32> = values.into_iter().collect();
+        let before = tree.len();
+
+        let inserted = tree.insert_many(batch.iter().cloned());
+        let duplicates = batch.iter().filter(|&&value| !reference.insert(value)).count();
+        inserted == tree.len() - before && inserted + duplicates == batch.len() && sorted_values(&tree) == reference.into_iter().collect::<Vec<_>>()
+    }
+
+    #[test]
+    fn quantiles_round_down() {
+        let tree: Tree<i32> = (1..11).collect();
+        assert_eq!(Some(&1), tree.quantile(0.0));
+        assert_eq!(Some(&5), tree.quantile(0.5));
+        assert_eq!(Some(&9), tree.quantile(0.9));
+        assert_eq!(Some(&10), tree.quantile(1.0));
+        assert_eq!(Some(&10), tree.quantile(7.0));
+        assert_eq!(Some(&1), tree.quantile(-1.0));
+        assert_eq!(Some(&1), tree.quantile(std::f64::NAN));
+
+        let odd: Tree<i32> = (1..6).collect();
+        assert_eq!(Some(&3), odd.quantile(0.5));
+        assert_eq!(None, Tree::<i32>::new().quantile(0.5));
+    }
+
+    // Starting from a value should give the rest of the sorted values from there on,
+    // and starting from the smallest should give them all.
+    #[quickcheck]
+    fn iter_from_property(values: Vec<i32>, lookups: Vec<i32>) -> bool {
+        let tree: Tree<i32> = values.iter().cloned().collect();
+        let sorted = sorted_values(&tree);
+        let from_first = tree.first().map_or(true, |first| tree.iter_from(first).eq(tree.iter_sorted()));
+        from_first && lookups.iter().chain(values.iter()).all(|value| {
+            let iter = tree.iter_from(value);
+            let rest: Vec<&i32> = sorted.iter().filter(|&other| other >= value).collect();
+            iter.len() == rest.len() && iter.collect::<Vec<_>>() == rest
+        })
+    }
+
+    // Finding in a range should give the first match in order, and stop there.
+    #[quickcheck]
+    fn find_in_range_property(values: Vec<i32>, start: i32, end: i32, modulus: u8) -> bool {
+        let tree: Tree<i32> = values.iter().cloned().collect();
+        let modulus = modulus as i32 % 7 + 1;
+        let mut checked = 0;
+        let found = tree.find_in_range(start..end, |&value| {
+            checked += 1;
+            value % modulus == 0
+        });
+        let expected = sorted_values(&tree).into_iter().find(|&value| value >= start && value < end && value % modulus == 0);
+        let before = tree.range(start..end).take_while(|&&value| Some(value) != expected).count();
+        found.cloned() == expected && checked == before + found.is_some() as usize
+    }
+
+    // The ends of a range should be the first and last values iterating over it gives.
+    #[quickcheck]
+    fn range_bounds_property(values: Vec<i32>, start: i32, end: i32, inclusive: bool) -> bool {
+        let tree: Tree<i32> = values.iter().cloned().collect();
+        fn ends<'a>(range: Vec<&'a i32>) -> Option<(&'a i32, &'a i32)> {
+            range.first().map(|&first| (first, *range.last().unwrap()))
+        }
+        let bounded = if inclusive {
+            tree.range_bounds(start..=end) == ends(tree.range(start..=end).collect())
+        } else {
+            tree.range_bounds(start..end) == ends(tree.range(start..end).collect())
+        };
+        bounded && tree.range_bounds(..) == tree.first().map(|first| (first, tree.last().unwrap())) &&
+            tree.range_bounds(start..) == ends(tree.range(start..).collect()) &&
+            tree.range_bounds(..end) == ends(tree.range(..end).collect())
+    }
+
+    // The same read-only code should give the same answers whether it's handed a
+    // BTreeSet or a Tree, without changing anything but the type.
+    #[quickcheck]
+    fn reads_like_btreeset(values: Vec<i32>, lookup: i32) -> bool {
+        macro_rules! read {
+            ($set:expr) => {{
+                let set = $set;
+                (set.len(), set.is_empty(), set.contains(&lookup), set.first().cloned(), set.last().cloned(),
+                 set.iter().cloned().collect::<Vec<i32>>(), set.range(lookup..).cloned().collect::<Vec<i32>>(),
+                 set.range(..=lookup).count())
+            }}
+        }
+        read!(values.iter().cloned().collect::<std::collections::BTreeSet<i32>>()) == read!(values.iter().cloned().collect::<Tree<i32>>())
+    }
+
+    // Everything ordered should come out the other way around with Reverse: iterating
+    // and popping from the front go from largest to smallest, and a range from a
+    // bigger value to a smaller one is what's between them, largest first.
+    #[test]
+    fn reverse_orders_largest_first() {
+        let mut tree = Tree::reversed();
+        for &value in &[5, 1, 9, 3, 7, 2, 8, 4, 6, 10] {
+            tree.insert(Reverse(value));
+        }
+        assert!(tree.check_invariants().is_ok());
+
+        let unwrap = |values: Vec<&Reverse<i32>>| values.into_iter().map(|&Reverse(value)| value).collect::<Vec<i32>>();
+        assert_eq!(vec![10, 9, 8, 7, 6, 5, 4, 3, 2, 1], unwrap(tree.iter().collect()));
+        assert_eq!(vec![7, 6, 5, 4, 3], unwrap(tree.range(Reverse(7)..=Reverse(3)).collect()));
+        assert_eq!(vec![2, 1], unwrap(tree.range(Reverse(2)..).collect()));
+        assert_eq!((Some(&Reverse(10)), Some(&Reverse(1))), (tree.first(), tree.last()));
+        assert_eq!(3, tree.count_less_than(&Reverse(7)));
+        assert!(tree.contains(&Reverse(4)));
+
+        assert_eq!(Some(Reverse(1)), tree.pop_last());
+        let mut popped = Vec::new();
+        while let Some(Reverse(value)) = tree.pop_first() {
+            popped.push(value);
+            assert!(tree.check_invariants().is_ok());
+        }
+        assert_eq!(vec![10, 9, 8, 7, 6, 5, 4, 3, 2], popped);
+    }
+
+    // Each count should match counting the sorted values by hand, and the strict and
+    // inclusive counts from either side should add up to the whole tree.
+    #[quickcheck]
+    fn counts_property(values: Vec<i32>, lookups: Vec<i32>) -> bool {
+        let tree: Tree<i32> = values.iter().cloned().collect();
+        let sorted = sorted_values(&tree);
+        lookups.iter().chain(values.iter()).all(|value| {
+            tree.count_less_than(value) == sorted.iter().filter(|&other| other < value).count() &&
+                tree.count_at_most(value) == sorted.iter().filter(|&other| other <= value).count() &&
+                tree.count_greater_than(value) == sorted.iter().filter(|&other| other > value).count() &&
+                tree.count_at_least(value) == sorted.iter().filter(|&other| other >= value).count() &&
+                tree.count_less_than(value) + tree.count_at_least(value) == tree.len() &&
+                tree.count_at_most(value) + tree.count_greater_than(value) == tree.len()
+        })
+    }
+
+    // Bisecting should find the same positions as bisecting the sorted values would,
+    // with the ends of the tree for values outside it.
+    #[quickcheck]
+    fn bisect_property(values: Vec<i32>, lookups: Vec<i32>) -> bool {
+        let tree: Tree<i32> = values.iter().cloned().collect();
+        let sorted = sorted_values(&tree);
+        let below = tree.first().and_then(|first| first.checked_sub(1));
+        let above = tree.last().and_then(|last| last.checked_add(1));
+        let ends = below.map_or(true, |below| tree.bisect_left(&below) == 0 && tree.bisect_right(&below) == 0) &&
+            above.map_or(true, |above| tree.bisect_left(&above) == tree.len() && tree.bisect_right(&above) == tree.len());
+        ends && values.iter().all(|value| tree.bisect_left(value) == tree.rank(value) && tree.bisect_right(value) == tree.rank(value) + 1) &&
+            lookups.iter().all(|value| {
+                tree.bisect_left(value) == sorted.partition_point(|other| other < value) &&
+                    tree.bisect_right(value) == sorted.partition_point(|other| other <= value)
+            })
+    }
+
+    // Floor and ceiling should find the nearest values at or below and at or above,
+    // just like searching the sorted values by hand.
+    #[quickcheck]
+    fn floor_ceiling_property(values: Vec<i32>, lookups: Vec<i32>) -> bool {
+        let tree: Tree<i32> = values.iter().cloned().collect();
+        let sorted = sorted_values(&tree);
+        lookups.iter().chain(values.iter()).all(|value| {
+            tree.floor(value) == sorted.iter().filter(|&other| other <= value).last() &&
+                tree.ceiling(value) == sorted.iter().find(|&other| other >= value) &&
+                tree.get(value) == sorted.iter().find(|&other| other == value)
+        })
+    }
+
+    // The neighbours should be the nearest values strictly either side, whether or not
+    // the value itself is in the tree.
+    #[quickcheck]
+    fn neighbors_property(values: Vec<i32>, lookups: Vec<i32>) -> bool {
+        let tree: Tree<i32> = values.iter().cloned().collect();
+        let sorted = sorted_values(&tree);
+        lookups.iter().chain(values.iter()).all(|value| {
+            tree.neighbors(value) == (sorted.iter().filter(|&other| other < value).last(), sorted.iter().find(|&other| other > value))
+        })
+    }
+
+    #[test]
+    fn stats_describe_the_shape() {
+        assert_eq!(TreeStats {len: 0, height: 0, min: None, max: None, max_imbalance: 0, leaves: 0, average_leaf_depth: 0.0},
+                   Tree::<i32>::new().stats());
+
+        // Seven values in order make a perfect tree, with all four leaves at depth 2.
+        let tree = Tree::from_sorted((1..8).collect());
+        assert_eq!(TreeStats {len: 7, height: 3, min: Some(&1), max: Some(&7), max_imbalance: 0, leaves: 4, average_leaf_depth: 2.0},
+                   tree.stats());
+
+        // Adding an eighth hangs a leaf off one of them, one level further down.
+        let mut tree = tree;
+        tree.insert(8);
+        assert_eq!(TreeStats {len: 8, height: 4, min: Some(&1), max: Some(&8), max_imbalance: 1, leaves: 4, average_leaf_depth: 2.25},
+                   tree.stats());
+    }
+
+    // Taking a value should hand back the stored one, not the one it was looked up by,
+    // and putting it back should give the same tree of values as before.
+    #[test]
+    fn take_returns_stored_value() {
+        let mut tree = Tree::new();
+        for key in 0..20 {
+            tree.insert(Keyed {key: key, label: "stored"});
+        }
+
+        let taken = tree.take(&Keyed {key: 7, label: "query"}).expect("7 is in the tree");
+        assert_eq!("stored", taken.label);
+        assert_eq!(19, tree.len());
+        assert!(tree.take(&Keyed {key: 7, label: "query"}).is_none());
+        assert!(tree.is_valid_avl());
+
+        tree.insert(taken);
+        let keys: Vec<i32> = tree.iter_sorted().map(|value| value.key).collect();
+        assert_eq!((0..20).collect::<Vec<_>>(), keys);
+        assert!(tree.iter_sorted().all(|value| value.label == "stored"));
+    }
+
+    // With memory to spare, trying to insert should do just what inserting does.
+    #[quickcheck]
+    fn try_insert_property(values: Vec<i32>) -> bool {
+        let (mut tried, mut inserted) = (Tree::new(), Tree::new());
+        let same_answers = values.iter().all(|&value| tried.try_insert(value) == Ok(inserted.insert(value)));
+        same_answers && tried.len() == inserted.len() && sorted_values(&tried) == sorted_values(&inserted) && tried.is_valid_avl()
+    }
+
+    // Walking the nodes by hand should find the same values, in the same order, as
+    // iterating does, with heights and sizes that add up.
+    #[quickcheck]
+    fn node_ref_property(values: Vec<i32>) -> bool {
+        let mut tree = Tree::new();
+        for &value in &values {
+            tree.insert(value);
+        }
+
+        // Returns the subtree's height, or None if its metadata or size is off.
+        fn walk(node: Option<NodeRef<i32>>, found: &mut Vec<i32>) -> Option<i8> {
+            let node = match node {
+                Some(node) => node,
+                None => return Some(0)
+            };
+            let left = walk(node.left(), found)?;
+            found.push(*node.value());
+            let right = walk(node.right(), found)?;
+            let size = 1 + node.left().map_or(0, |left| left.size()) + node.right().map_or(0, |right| right.size());
+            if node.metadata() == (left, right) && node.size() == size { Some(1 + max(left, right)) } else { None }
+        }
+
+        let mut found = Vec::new();
+        walk(tree.root(), &mut found).is_some() && found == sorted_values(&tree) && tree.root().map_or(0, |root| root.size()) == tree.len()
+    }
+
+    // Rotations move whole subtrees around with take and swap, and never need a stand-in
+    // value, so values with no Default or conversion from a number work no matter
+    // which rotations a sequence of inserts and removes takes.
+    #[test]
+    fn rotations_need_no_placeholder() {
+        #[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+        struct Opaque(u32);
+
+        let mut tree = Tree::new();
+        for value in (0..50).chain((100..150).rev()).chain((0..25).flat_map(|i| vec![50 + i, 99 - i])) {
+            tree.insert(Opaque(value));
+        }
+        for value in (0..150).filter(|value| value % 3 == 0) {
+            assert!(tree.remove(&Opaque(value)));
+        }
+        assert_eq!(100, tree.len());
+        assert_eq!(Ok(()), tree.check_invariants());
+    }
+
+    // Zero sized values are all equal to each other, so a tree of them holds at most
+    // one. Nothing about moving values around during rotations or removals should care
+    // that they take up no space.
+    #[test]
+    fn zero_sized_values() {
+        let mut tree = Tree::new();
+        assert!(tree.insert(()));
+        assert!(!tree.insert(()));
+        assert_eq!(Some(()), tree.replace(()));
+        assert_eq!(1, tree.len());
+        assert_eq!(Some(&()), tree.first());
+        assert_eq!(vec![&()], tree.iter_sorted().collect::<Vec<_>>());
+        assert!(tree.remove(&()));
+        assert!(tree.is_empty());
+        assert!(tree.check_invariants().is_ok());
+    }
+
+    // The pairs should be exactly each value with the one after it, and there should be
+    // one fewer of them than values.
+    #[quickcheck]
+    fn pairs_property(values: Vec<i32>) -> bool {
+        let mut tree = Tree::new();
+        for &value in &values {
+            tree.insert(value);
+        }
+
+        let sorted = sorted_values(&tree);
+        let pairs: Vec<(i32, i32)> = tree.pairs().map(|(&a, &b)| (a, b)).collect();
+        let expected: Vec<(i32, i32)> = sorted.windows(2).map(|pair| (pair[0], pair[1])).collect();
+        pairs == expected && tree.pairs().len() == expected.len()
+    }
+
+    // The symmetric difference should agree with a BTreeSet's, whichever tree it's
+    // taken from.
+    #[quickcheck]
+    fn symmetric_difference_property(a: Vec<i32>, b: Vec<i32>) -> bool {
+        let (first, second): (Tree<i32>, Tree<i32>) = (a.iter().cloned().collect(), b.iter().cloned().collect());
+        let (first_set, second_set): (std::collections::BTreeSet<i32>, std::collections::BTreeSet<i32>) = (a.into_iter().collect(), b.into_iter().collect());
+
+        let expected: Vec<&i32> = first_set.symmetric_difference(&second_set).collect();
+        first.symmetric_difference(&second).collect::<Vec<_>>() == expected &&
+            second.symmetric_difference(&first).collect::<Vec<_>>() == expected
+    }
+
+    // Iterating in order should give back the values sorted and deduplicated, and should
+    // know exactly how many values it has left the whole way through.
+    #[quickcheck]
+    fn iter_sorted_property(values: Vec<i32>) -> bool {
+        let mut tree = Tree::new();
+        for &value in &values {
+            tree.insert(value);
+        }
+
+        let mut expected = values.clone();
+        expected.sort();
+        expected.dedup();
+
+        let mut iter = tree.iter_sorted();
+        let mut found = Vec::new();
+        while iter.len() == expected.len() - found.len() {
+            match iter.next() {
+                Some(&value) => found.push(value),
+                None => break
+            }
+        }
+        iter.len() == 0 && iter.size_hint() == (0, Some(0)) && found == expected
+    }
+
+    #[quickcheck]
+    fn first_last_property(values: Vec<i32>) -> bool {
+        let mut tree = Tree::new();
+        for &value in &values {
+            tree.insert(value);
+        }
+
+        tree.first() == values.iter().min() && tree.last() == values.iter().max()
+    }
+
+    // Every index should find the same value as iterating that far would, and indexes
+    // past the end should find nothing.
+    #[quickcheck]
+    fn nth_property(values: Vec<i32>) -> bool {
+        let mut tree = Tree::new();
+        for &value in &values {
+            tree.insert(value);
+        }
+
+        (0..tree.len() + 2).all(|i| tree.nth(i) == tree.iter_sorted().nth(i))
+    }
+
+    // Counting and iterating over every kind of range, and asking whether anything is in
+    // one, should agree with filtering the values by hand.
+    #[quickcheck]
+    fn range_property(values: Vec<i32>, start: i32, end: i32) -> bool {
+        let mut tree = Tree::new();
+        for &value in &values {
+            tree.insert(value);
+        }
+
+        fn check<R: RangeBounds<i32>+Clone>(tree: &Tree<i32>, bounds: R) -> bool {
+            let expected: Vec<i32> = tree.iter_sorted().cloned().filter(|value| bounds.contains(value)).collect();
+            let found: Vec<i32> = tree.range(bounds.clone()).cloned().collect();
+            found == expected && tree.count_range(bounds) == expected.len()
+        }
+
+        check(&tree, start..end) && check(&tree, start..=end) && check(&tree, ..end) && check(&tree, start..) &&
+            check(&tree, ..) && check(&tree, (Bound::Excluded(start), Bound::Excluded(end))) &&
+            (0..tree.len()).all(|i| tree.rank(tree.nth(i).unwrap()) == i) &&
+            tree.any_in_range(&start, &end) == tree.iter_sorted().any(|value| (start..=end).contains(value))
+    }
+
+    // Draws the tree sideways, right children above and left children below.
+    #[test]
+    fn debug_draws_the_tree() {
+        let mut tree = Tree::new();
+        for value in 1..9 {
+            tree.insert(value);
+        }
+
+        let drawn = format!("{:?}", tree);
+        assert_eq!(drawn.lines().collect::<Vec<_>>(), vec![
+            "            8 (0, 0)",
+            "        7 (0, 1)",
+            "    6 (1, 2)",
+            "        5 (0, 0)",
+            "4 (2, 3)",
+            "        3 (0, 0)",
+            "    2 (1, 1)",
+            "        1 (0, 0)",
+        ]);
+        assert_eq!("(empty)\n", format!("{:?}", Tree::<i32>::new()));
+    }
+
+    // Breaking each invariant by hand should get reported, naming the broken node, and
+    // should make is_valid_avl say no.
+    #[test]
+    fn check_invariants_finds_corruption() {
+        let mut tree = Tree::new();
+        for value in 1..8 {
+            tree.insert(value);
+        }
+        assert_eq!(Ok(()), tree.check_invariants());
+        assert!(tree.is_valid_avl());
+
+        tree.root.as_mut().unwrap().right.as_mut().unwrap().value = 3;
+        assert_eq!(Err("node 3 is in the right subtree of 4 but isn't greater than it".to_string()), tree.check_invariants());
+        assert!(!tree.is_valid_avl());
+        tree.root.as_mut().unwrap().right.as_mut().unwrap().value = 6;
+
+        tree.root.as_mut().unwrap().metadata = (2, 1);
+        assert_eq!(Err("node 4 has metadata (2, 1) but its children have heights (2, 2)".to_string()), tree.check_invariants());
+        assert!(!tree.is_valid_avl());
+        tree.root.as_mut().unwrap().metadata = (2, 2);
+
+        tree.root.as_mut().unwrap().size = 8;
+        assert_eq!(Err("node 4 has size 8 but its subtree has 7 values".to_string()), tree.check_invariants());
+        assert!(!tree.is_valid_avl());
+        tree.root.as_mut().unwrap().size = 7;
+
+        tree.len = 6;
+        assert_eq!(Err("the tree has 7 nodes but its length is 6".to_string()), tree.check_invariants());
+        assert!(!tree.is_valid_avl());
+    }
+
+    // Building from sorted values shouldn't rotate at all, and neither should rebuilding.
+    // Inserting in ascending order rotates all the time, and the tree should remember
+    // that through a rebuild or being cleared, and pass it on when it's appended or
+    // joined to another tree.
+    #[test]
+    #[cfg(feature = "rotation-count")]
+    fn rotation_count_is_tracked() {
+        let tree = Tree::from_sorted((0..1000).collect());
+        assert_eq!(0, tree.rotation_count());
+
+        let mut tree = Tree::new();
+        for value in 0..1000 {
+            tree.insert(value);
+        }
+        let rotations = tree.rotation_count();
+        assert!(rotations > 0);
+
+        tree.retain(|value| value % 2 == 0);
+        assert_eq!(rotations, tree.rotation_count());
+        tree.clear();
+        assert_eq!(rotations, tree.rotation_count());
+
+        let mut other = Tree::new();
+        for value in 0..1000 {
+            other.insert(value);
+        }
+        let mut tree = Tree::from_sorted((2000..2010).collect());
+        tree.append(&mut other);
+        assert!(tree.rotation_count() >= rotations);
+        assert_eq!(0, other.rotation_count());
+
+        let other = Tree::from_sorted((3000..3010).collect());
+        assert!(Tree::join(tree, other).rotation_count() >= rotations);
+    }
+
+    // Collapsing values within 1 of the last one kept should keep the first of each run,
+    // and compare against the kept value rather than the one just dropped.
+    #[test]
+    fn dedup_by_collapses_near_values() {
+        let mut tree: Tree<i32> = vec![1, 2, 3, 5, 6, 9, 11, 12].into_iter().collect();
+        tree.dedup_by(|kept, value| value - kept <= 1);
+        assert_eq!(vec![1, 3, 5, 9, 11], sorted_values(&tree));
+        assert_eq!(5, tree.len());
+        assert!(tree.is_valid_avl());
+    }
+
+    // Retaining the even values should leave exactly the even values, in a valid tree.
+    #[quickcheck]
+    fn retain_property(values: Vec<i32>) -> bool {
+        let mut tree = Tree::new();
+        for &value in &values {
+            tree.insert(value);
+        }
+
+        let mut expected: Vec<i32> = values.into_iter().filter(|value| value % 2 == 0).collect();
+        expected.sort();
+        expected.dedup();
+
+        tree.retain(|value| value % 2 == 0);
+        tree.len() == expected.len() && sorted_values(&tree) == expected && tree.check_invariants().is_ok()
+    }
 }
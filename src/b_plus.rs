@@ -1,11 +1,7 @@
-extern crate quickcheck;
-extern crate rand;
 extern crate std;
 
 use std::mem;
 use std::fmt::Debug;
-use quickcheck::Arbitrary;
-use quickcheck::Gen;
 
 #[derive(Debug,Eq,PartialEq)]
 struct BPlusTree<T: Clone+Debug+Ord> {
@@ -126,33 +122,38 @@ impl <T: Clone+Debug+Ord> BPlusTree<T> {
     fn insert(&mut self, value: T) {}
 }
 
-#[test]
-fn search_basically_works() {
-    //example b+ tree cribbed from http://www.cburch.com/cs/340/reading/btree/, first example under insertion section.
-    let mut bt: BPlusTree<i32> = BPlusTree {
-        capacity: 10,
-        kind: BPlusNodeType::Root,
-        keys: [Some(16), None, None, None, None, None, None, None, None],
-        data: [None, None, None, None, None, None, None, None, None],
-        children: [
-            Some(box BPlusTree {
-                capacity: 10,
-                kind: BPlusNodeType::Leaf,
-                keys: [Some(1), Some(4), Some(9), None, None, None, None, None, None],
-                children: [None, None, None, None, None, None, None, None, None, None],
-                data: [Some(box 1), Some(box 4), Some(box 9), None, None, None, None, None, None],
-            }),
-            Some(box BPlusTree {
-                capacity: 10,
-                kind: BPlusNodeType::Leaf,
-                keys: [Some(16), Some(25), None, None, None, None, None, None, None],
-                children: [None, None, None, None, None, None, None, None, None, None],
-                data: [Some(box 16), Some(box 25), None, None, None, None, None, None, None],
-            }), None, None, None, None, None, None, None, None]
-    };
-
-    assert_eq!(bt.clone().children[1], Some(box bt.search(1000).clone()));
-    assert_eq!(bt.clone().children[0], Some(box bt.search(-1000).clone()));
-    assert_eq!(bt.clone().children[0], Some(box bt.search(0).clone()));
-    assert_eq!(bt.clone().children[1], Some(box bt.search(16).clone()));
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn search_basically_works() {
+        //example b+ tree cribbed from http://www.cburch.com/cs/340/reading/btree/, first example under insertion section.
+        let mut bt: BPlusTree<i32> = BPlusTree {
+            capacity: 10,
+            kind: BPlusNodeType::Root,
+            keys: [Some(16), None, None, None, None, None, None, None, None],
+            data: [None, None, None, None, None, None, None, None, None],
+            children: [
+                Some(box BPlusTree {
+                    capacity: 10,
+                    kind: BPlusNodeType::Leaf,
+                    keys: [Some(1), Some(4), Some(9), None, None, None, None, None, None],
+                    children: [None, None, None, None, None, None, None, None, None, None],
+                    data: [Some(box 1), Some(box 4), Some(box 9), None, None, None, None, None, None],
+                }),
+                Some(box BPlusTree {
+                    capacity: 10,
+                    kind: BPlusNodeType::Leaf,
+                    keys: [Some(16), Some(25), None, None, None, None, None, None, None],
+                    children: [None, None, None, None, None, None, None, None, None, None],
+                    data: [Some(box 16), Some(box 25), None, None, None, None, None, None, None],
+                }), None, None, None, None, None, None, None, None]
+        };
+
+        assert_eq!(bt.clone().children[1], Some(box bt.search(1000).clone()));
+        assert_eq!(bt.clone().children[0], Some(box bt.search(-1000).clone()));
+        assert_eq!(bt.clone().children[0], Some(box bt.search(0).clone()));
+        assert_eq!(bt.clone().children[1], Some(box bt.search(16).clone()));
+    }
 }
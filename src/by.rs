@@ -0,0 +1,136 @@
+use core::cmp::Ordering;
+use avl::SortedIterator;
+use avl::Tree;
+
+// A Tree needs its values to be Ord, which rules out floats: NaN isn't equal to
+// anything, even itself, so f64 is only PartialOrd. A TreeBy orders its values with a
+// comparison function instead, like f64::total_cmp, which puts every float, NaNs
+// included, into one total order (-0.0 comes before 0.0, and NaNs with the sign bit
+// set come before everything else, the rest after), so the tree never has to decide
+// what to do with values that don't compare. Whether that's the right place for NaNs
+// is up to whoever picks the comparison; if they shouldn't be in the tree at all,
+// check before inserting.
+//
+// Underneath it's an ordinary Tree, of values that each carry the comparison along
+// with them, so that they can be Ord. That costs a pointer per value, but means all
+// the balancing works exactly the same. The comparison has to be a total order, or
+// lookups will miss values that are there.
+struct By<V> {
+    value: V,
+    compare: fn(&V, &V) -> Ordering
+}
+
+impl <V> PartialEq for By<V> {
+    fn eq(&self, other: &By<V>) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl <V> Eq for By<V> {}
+
+impl <V> PartialOrd for By<V> {
+    fn partial_cmp(&self, other: &By<V>) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl <V> Ord for By<V> {
+    fn cmp(&self, other: &By<V>) -> Ordering {
+        (self.compare)(&self.value, &other.value)
+    }
+}
+
+pub struct TreeBy<V> {
+    tree: Tree<By<V>>,
+    compare: fn(&V, &V) -> Ordering
+}
+
+impl <V> TreeBy<V> {
+    // An empty tree that orders its values with compare, e.g. TreeBy::new(f64::total_cmp).
+    pub fn new(compare: fn(&V, &V) -> Ordering) -> Self {
+        TreeBy {tree: Tree::new(), compare: compare}
+    }
+
+    pub fn len(&self) -> usize {
+        self.tree.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tree.is_empty()
+    }
+
+    pub fn insert(&mut self, value: V) -> bool {
+        self.tree.insert(By {value: value, compare: self.compare})
+    }
+
+    // Lookups don't need to wrap the value up, they just compare against each value
+    // on the way down.
+    pub fn contains(&self, value: &V) -> bool {
+        let compare = self.compare;
+        self.tree.get_by(|other| compare(value, &other.value)).is_some()
+    }
+
+    pub fn remove(&mut self, value: &V) -> Option<V> {
+        let compare = self.compare;
+        self.tree.remove_by(|other| compare(value, &other.value)).map(|removed| removed.value)
+    }
+
+    pub fn first(&self) -> Option<&V> {
+        self.tree.first().map(|first| &first.value)
+    }
+
+    pub fn last(&self) -> Option<&V> {
+        self.tree.last().map(|last| &last.value)
+    }
+
+    // Iterates over the values in the comparison's order.
+    pub fn iter_sorted<'a>(&'a self) -> Iter<'a, V> {
+        Iter {values: self.tree.iter_sorted()}
+    }
+}
+
+pub struct Iter<'a, V: 'a> {
+    values: SortedIterator<'a, By<V>>
+}
+
+impl <'a, V: 'a> Iterator for Iter<'a, V> {
+    type Item = &'a V;
+
+    fn next(&mut self) -> Option<&'a V> {
+        self.values.next().map(|by| &by.value)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.values.size_hint()
+    }
+}
+
+impl <'a, V: 'a> ExactSizeIterator for Iter<'a, V> {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Floats ordered by total_cmp come out in numeric order, with -0.0 before 0.0
+    // rather than equal to it, and NaN after everything else.
+    #[test]
+    fn floats_by_total_cmp() {
+        let mut tree = TreeBy::new(f64::total_cmp);
+        for &value in &[2.5, 0.0, std::f64::NAN, -1.0, -0.0, 1e10, 0.0, std::f64::NEG_INFINITY] {
+            tree.insert(value);
+        }
+
+        assert_eq!(7, tree.len());
+        let values: Vec<f64> = tree.iter_sorted().cloned().collect();
+        assert_eq!(vec![std::f64::NEG_INFINITY, -1.0, -0.0, 0.0, 2.5, 1e10], values[..6].to_vec());
+        assert!(values[2].is_sign_negative() && values[3].is_sign_positive());
+        assert!(tree.last().unwrap().is_nan());
+
+        assert!(tree.contains(&-0.0));
+        assert_eq!(Some(0.0), tree.remove(&0.0));
+        assert!(!tree.contains(&0.0));
+        assert!(tree.contains(&-0.0));
+        assert!(tree.contains(&std::f64::NAN));
+        assert_eq!(Some(&std::f64::NEG_INFINITY), tree.first());
+    }
+}
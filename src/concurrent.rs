@@ -0,0 +1,114 @@
+extern crate std;
+
+use std::ops::RangeBounds;
+use std::sync::RwLock;
+use std::sync::RwLockReadGuard;
+use avl::Tree;
+
+// A tree that can be shared between threads, for read heavy workloads. It's a Tree
+// behind a RwLock, so any number of threads can read at once, and writers wait for
+// the readers to finish and then get the tree to themselves. Each method holds the
+// lock only for as long as it takes to do its one operation, so for anything that
+// needs a consistent view across several reads, use read() and hold onto the guard.
+//
+// Like any std lock, if a thread panics while holding the lock for writing the tree
+// might be left halfway through a rebalance, so every method panics from then on
+// rather than hand out a possibly broken tree.
+pub struct SyncTree<V: Ord> {
+    tree: RwLock<Tree<V>>
+}
+
+impl <V: Ord> Default for SyncTree<V> {
+    fn default() -> Self {
+        SyncTree::new()
+    }
+}
+
+impl <V: Ord> SyncTree<V> {
+    pub fn new() -> Self {
+        SyncTree {tree: RwLock::new(Tree::new())}
+    }
+
+    pub fn read<'a>(&'a self) -> RwLockReadGuard<'a, Tree<V>> {
+        self.tree.read().expect("a writer panicked while holding the lock")
+    }
+
+    pub fn len(&self) -> usize {
+        self.read().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.read().is_empty()
+    }
+
+    pub fn contains(&self, value: &V) -> bool {
+        self.read().contains(value)
+    }
+
+    pub fn insert(&self, value: V) -> bool {
+        self.tree.write().expect("a writer panicked while holding the lock").insert(value)
+    }
+
+    pub fn remove(&self, value: &V) -> bool {
+        self.tree.write().expect("a writer panicked while holding the lock").remove(value)
+    }
+}
+
+impl <V: Ord+Clone> SyncTree<V> {
+    // The values within some bounds. An iterator would have to hold the read lock for
+    // as long as it's alive, so instead this copies the values out and releases the
+    // lock before returning them.
+    pub fn range<R: RangeBounds<V>>(&self, bounds: R) -> Vec<V> {
+        self.read().range(bounds).cloned().collect()
+    }
+}
+
+impl <V: Ord> From<Tree<V>> for SyncTree<V> {
+    fn from(tree: Tree<V>) -> Self {
+        SyncTree {tree: RwLock::new(tree)}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Everything in a Tree is owned through Boxes, so it's Send and Sync exactly when its
+    // values are, and so are the iterators borrowing from it. This only compiles if so.
+    #[test]
+    fn trees_are_send_and_sync() {
+        fn assert_send_sync<T: Send+Sync>() {}
+        assert_send_sync::<Tree<i32>>();
+        assert_send_sync::<::avl::SortedIterator<String>>();
+        assert_send_sync::<SyncTree<String>>();
+    }
+
+    // Readers on several threads should all see every value that was there before any of
+    // them started, while a writer keeps adding more.
+    #[test]
+    fn concurrent_reads() {
+        use std::sync::Arc;
+        use std::thread;
+
+        let tree = Arc::new(SyncTree::new());
+        for value in 0..1000 {
+            tree.insert(value * 2);
+        }
+
+        let readers: Vec<_> = (0..4).map(|_| {
+            let tree = tree.clone();
+            thread::spawn(move || {
+                (0..1000).all(|value| tree.contains(&(value * 2))) && tree.range(10..20).len() >= 5
+            })
+        }).collect();
+
+        for value in 0..1000 {
+            tree.insert(value * 2 + 1);
+        }
+
+        assert!(readers.into_iter().all(|reader| reader.join().unwrap()));
+        assert_eq!(2000, tree.len());
+        assert!(tree.remove(&0));
+        assert!(!tree.contains(&0));
+    }
+}
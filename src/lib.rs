@@ -1,9 +1,29 @@
-#![feature(box_syntax, box_patterns, slice_patterns)]
-#![feature(rand)]
-#![feature(plugin)]
-#![plugin(quickcheck_macros)]
+#![cfg_attr(not(any(test, feature = "std")), no_std)]
+#![feature(box_syntax, box_patterns, slice_patterns, allocator_api)]
+#![cfg_attr(test, feature(plugin))]
+#![cfg_attr(test, plugin(quickcheck_macros))]
 
+// The trees only ever need somewhere to put their nodes, so everything but SyncTree
+// gets by with just an allocator. Building without the default std feature gives a
+// no_std crate, with Box, Vec and the rest coming from alloc instead. The little crate
+// in tests/no_std builds against it that way, to check nothing has slipped in that
+// needs std.
+#[macro_use]
+extern crate alloc;
+// no_std puts core at the root for us, std doesn't.
+#[cfg(any(test, feature = "std"))]
+extern crate core;
+#[cfg(feature = "rayon")]
+extern crate rayon;
+#[cfg(test)]
 extern crate quickcheck;
 
-mod avl;
+pub mod arena;
+pub mod avl;
+pub mod by;
+#[cfg(feature = "std")]
+pub mod concurrent;
+pub mod map;
+pub mod persistent;
+#[cfg(feature = "std")]
 mod b_plus;
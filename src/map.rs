@@ -0,0 +1,735 @@
+use alloc::vec::Vec;
+use core::cmp::Ordering;
+use core::iter::FromIterator;
+use core::mem;
+use core::ops::RangeBounds;
+use avl::after_start;
+use avl::before_end;
+use avl::Drain;
+use avl::SortedIterator;
+use avl::SortedIteratorMut;
+use avl::Tree;
+
+// A map is a tree of entries, each a key with a value attached, that are ordered by
+// their keys alone. Everything about keeping the tree balanced works exactly as it
+// does for a set of values; the only difference is that lookups go by key, so they
+// don't need a value to search with.
+#[derive(Debug)]
+struct Entry<K, V> {
+    key: K,
+    value: V
+}
+
+impl <K: Ord, V> PartialEq for Entry<K, V> {
+    fn eq(&self, other: &Entry<K, V>) -> bool {
+        self.key == other.key
+    }
+}
+
+impl <K: Ord, V> Eq for Entry<K, V> {}
+
+impl <K: Ord, V> PartialOrd for Entry<K, V> {
+    fn partial_cmp(&self, other: &Entry<K, V>) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl <K: Ord, V> Ord for Entry<K, V> {
+    fn cmp(&self, other: &Entry<K, V>) -> Ordering {
+        self.key.cmp(&other.key)
+    }
+}
+
+pub struct Map<K: Ord, V> {
+    tree: Tree<Entry<K, V>>
+}
+
+impl <K: Ord, V> Default for Map<K, V> {
+    fn default() -> Self {
+        Map::new()
+    }
+}
+
+// Collecting into a map builds it in one go, the same way collecting into a Tree does:
+// sort the entries by key, drop duplicate keys, and build the tree from what's left.
+// Like inserting them one at a time, when a key turns up more than once the last
+// value for it wins. Reversing the entries first puts the last of each key first,
+// and the sort is stable, so it stays first among its equals, where dedup keeps it.
+impl <K: Ord, V> FromIterator<(K, V)> for Map<K, V> {
+    fn from_iter<I: IntoIterator<Item=(K, V)>>(pairs: I) -> Self {
+        let mut entries: Vec<Entry<K, V>> = pairs.into_iter().map(|(key, value)| Entry {key: key, value: value}).collect();
+        entries.reverse();
+        entries.sort();
+        entries.dedup();
+        Map {tree: Tree::from_sorted(entries)}
+    }
+}
+
+impl <K: Ord, V> Map<K, V> {
+    pub fn new() -> Self {
+        Map {tree: Tree::new()}
+    }
+
+    pub fn len(&self) -> usize {
+        self.tree.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tree.is_empty()
+    }
+
+    // Puts the value in the map under the key. If the key was already there, its
+    // value is replaced, and the old one handed back, but the key itself stays as it
+    // was.
+    pub fn insert(&mut self, key: K, value: V) -> Option<V> {
+        if self.get(&key).is_none() {
+            self.tree.insert(Entry {key: key, value: value});
+            return None
+        }
+        let old = self.get_mut(&key).expect("the key is in the map");
+        Some(mem::replace(old, value))
+    }
+
+    pub fn contains_key(&self, key: &K) -> bool {
+        self.get(key).is_some()
+    }
+
+    pub fn get(&self, key: &K) -> Option<&V> {
+        self.get_key_value(key).map(|(_, value)| value)
+    }
+
+    // The key that's actually stored along with its value, which can matter when equal
+    // keys aren't identical.
+    pub fn get_key_value(&self, key: &K) -> Option<(&K, &V)> {
+        self.tree.get_by(|entry| key.cmp(&entry.key)).map(|entry| (&entry.key, &entry.value))
+    }
+
+    // The value under the key, to change in place. Only the value is handed out, never
+    // the entry, since changing a key where it sits would put it out of order and break
+    // every lookup after it. To change a key, use change_key, which moves the entry.
+    pub fn get_mut(&mut self, key: &K) -> Option<&mut V> {
+        self.tree.get_mut_by(|entry| key.cmp(&entry.key)).map(|entry| &mut entry.value)
+    }
+
+    pub fn remove(&mut self, key: &K) -> Option<V> {
+        self.tree.remove_by(|entry| key.cmp(&entry.key)).map(|entry| entry.value)
+    }
+
+    // Moves the value under one key to another, which is how a priority queue changes
+    // an item's priority (Dijkstra's algorithm's decrease-key, say). Nothing happens,
+    // and this returns false, if there's nothing under the old key, or there's already
+    // something under the new one. Otherwise it's a remove and an insert, each of which
+    // keeps the tree balanced, so it's O(log n).
+    pub fn change_key(&mut self, old: &K, new: K) -> bool {
+        if self.get(old).is_none() || self.get(&new).is_some() {
+            return false
+        }
+        let value = self.remove(old).expect("the old key is in the map");
+        self.insert(new, value);
+        true
+    }
+
+    // Moves every entry from other into this map. When a key is in both, resolve gets
+    // the key, the value already here and the one coming in, and whatever it gives back
+    // is the value that's kept, under the key that was already here. Both maps' entries
+    // come out in order, so they're merged in one pass side by side, and the map is
+    // built back up perfectly balanced from the result, which is O(n + m).
+    pub fn merge_with<F: FnMut(&K, V, V) -> V>(&mut self, mut other: Map<K, V>, mut resolve: F) {
+        let mut merged = Vec::with_capacity(self.len() + other.len());
+        let (mut existing, mut incoming) = (self.tree.drain().peekable(), other.tree.drain().peekable());
+        loop {
+            let order = match (existing.peek(), incoming.peek()) {
+                (Some(entry), Some(other)) => entry.key.cmp(&other.key),
+                (Some(_), None) => Ordering::Less,
+                (None, Some(_)) => Ordering::Greater,
+                (None, None) => break
+            };
+            match order {
+                Ordering::Less => merged.extend(existing.next()),
+                Ordering::Greater => merged.extend(incoming.next()),
+                Ordering::Equal => {
+                    let (entry, other) = (existing.next().expect("there's an entry here"), incoming.next().expect("there's an entry there"));
+                    let value = resolve(&entry.key, entry.value, other.value);
+                    merged.push(Entry {key: entry.key, value: value});
+                }
+            }
+        }
+        drop(existing);
+        self.tree = Tree::from_sorted(merged);
+    }
+
+    // Keeps only the entries the predicate returns true for, like BTreeMap::retain,
+    // and the predicate can change the values of the ones it keeps along the way. Like
+    // Tree::retain this takes the whole tree apart and builds it back up, so it's O(n),
+    // and the tree comes out perfectly balanced.
+    pub fn retain_mut<F: FnMut(&K, &mut V) -> bool>(&mut self, mut f: F) {
+        self.tree.retain_mut(|entry| {
+            let Entry {ref key, ref mut value} = *entry;
+            f(key, value)
+        });
+    }
+
+    // A handle on the entry with the smallest key, to look at, change the value of, or
+    // remove, like BTreeMap's first_entry. Getting to either end of the tree is a walk
+    // down one of its spines, so whatever the handle is used for is O(log n).
+    pub fn first_entry<'a>(&'a mut self) -> Option<OccupiedEntry<'a, K, V>> {
+        if self.is_empty() {
+            return None
+        }
+        Some(OccupiedEntry {tree: &mut self.tree, end: End::First})
+    }
+
+    // The same, for the entry with the largest key.
+    pub fn last_entry<'a>(&'a mut self) -> Option<OccupiedEntry<'a, K, V>> {
+        if self.is_empty() {
+            return None
+        }
+        Some(OccupiedEntry {tree: &mut self.tree, end: End::Last})
+    }
+
+    // Iterates over the entries in ascending order of their keys.
+    pub fn iter<'a>(&'a self) -> Iter<'a, K, V> {
+        Iter {entries: self.tree.iter_sorted()}
+    }
+
+    // Iterates over the entries in ascending order of their keys, with the values
+    // handed out mutably. Each value is its own borrow, separate from its key and from
+    // every other value, so they can all be held at once, but the keys only ever come
+    // out shared, so the order can't be broken.
+    pub fn iter_mut<'a>(&'a mut self) -> IterMut<'a, K, V> {
+        IterMut {entries: self.tree.iter_sorted_mut()}
+    }
+
+    // The entries with keys within some bounds, in ascending order of their keys, with
+    // the values handed out mutably, like BTreeMap::range_mut. It starts the way the
+    // tree's range does, skipping everything before the start without visiting it, and
+    // stops at the first key past the end. As with iter_mut, every value is a borrow of
+    // its own, and the keys only ever come out shared.
+    pub fn range_mut<'a, R: RangeBounds<K>>(&'a mut self, bounds: R) -> RangeMut<'a, K, V, R> {
+        let entries = self.tree.iter_sorted_mut_from(|entry| after_start(bounds.start_bound(), &entry.key));
+        RangeMut {entries: entries, bounds: bounds, done: false}
+    }
+
+    pub fn keys<'a>(&'a self) -> Keys<'a, K, V> {
+        Keys {entries: self.tree.iter_sorted()}
+    }
+
+    pub fn values<'a>(&'a self) -> Values<'a, K, V> {
+        Values {entries: self.tree.iter_sorted()}
+    }
+
+    // The values in ascending order of their keys, to change in place. Like get_mut,
+    // this never hands out the keys mutably, so the order can't be broken.
+    pub fn values_mut<'a>(&'a mut self) -> ValuesMut<'a, K, V> {
+        ValuesMut {entries: self.tree.iter_sorted_mut()}
+    }
+
+    // Consumes the map, moving the keys out in ascending order, so nothing gets cloned.
+    // The map's nodes are taken apart as the keys come out, and the values are dropped
+    // along the way. Whatever's left if the iterator is dropped early is dropped with it.
+    pub fn into_keys(mut self) -> IntoKeys<K, V> {
+        IntoKeys {entries: self.tree.drain()}
+    }
+
+    // The same, but moving the values out, in ascending order of their keys.
+    pub fn into_values(mut self) -> IntoValues<K, V> {
+        IntoValues {entries: self.tree.drain()}
+    }
+}
+
+enum End {
+    First,
+    Last
+}
+
+// An entry that's known to be in the map, at one end or the other. It holds on to the
+// map, so the map can't change while the handle is around, and the entry it points at
+// is always there to be found again.
+pub struct OccupiedEntry<'a, K: 'a+Ord, V: 'a> {
+    tree: &'a mut Tree<Entry<K, V>>,
+    end: End
+}
+
+impl <'a, K: 'a+Ord, V: 'a> OccupiedEntry<'a, K, V> {
+    fn entry(&self) -> &Entry<K, V> {
+        match self.end {
+            End::First => self.tree.first(),
+            End::Last => self.tree.last()
+        }.expect("an occupied entry's map isn't empty")
+    }
+
+    fn entry_mut(&mut self) -> &mut Entry<K, V> {
+        match self.end {
+            End::First => self.tree.first_mut(),
+            End::Last => self.tree.last_mut()
+        }.expect("an occupied entry's map isn't empty")
+    }
+
+    pub fn key(&self) -> &K {
+        &self.entry().key
+    }
+
+    pub fn get(&self) -> &V {
+        &self.entry().value
+    }
+
+    pub fn get_mut(&mut self) -> &mut V {
+        &mut self.entry_mut().value
+    }
+
+    // The value, borrowed for as long as the map was, rather than just the handle.
+    pub fn into_mut(self) -> &'a mut V {
+        let entry = match self.end {
+            End::First => self.tree.first_mut(),
+            End::Last => self.tree.last_mut()
+        };
+        &mut entry.expect("an occupied entry's map isn't empty").value
+    }
+
+    // Replaces the value, handing back the old one.
+    pub fn insert(&mut self, value: V) -> V {
+        mem::replace(self.get_mut(), value)
+    }
+
+    // Takes the entry out of the map, which gets rebalanced on the way back up.
+    pub fn remove_entry(self) -> (K, V) {
+        let entry = match self.end {
+            End::First => self.tree.pop_first(),
+            End::Last => self.tree.pop_last()
+        }.expect("an occupied entry's map isn't empty");
+        (entry.key, entry.value)
+    }
+
+    pub fn remove(self) -> V {
+        self.remove_entry().1
+    }
+}
+
+pub struct Iter<'a, K: 'a+Ord, V: 'a> {
+    entries: SortedIterator<'a, Entry<K, V>>
+}
+
+impl <'a, K: 'a+Ord, V: 'a> Iterator for Iter<'a, K, V> {
+    type Item = (&'a K, &'a V);
+
+    fn next(&mut self) -> Option<(&'a K, &'a V)> {
+        self.entries.next().map(|entry| (&entry.key, &entry.value))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.entries.size_hint()
+    }
+}
+
+impl <'a, K: 'a+Ord, V: 'a> ExactSizeIterator for Iter<'a, K, V> {}
+
+pub struct IterMut<'a, K: 'a+Ord, V: 'a> {
+    entries: SortedIteratorMut<'a, Entry<K, V>>
+}
+
+impl <'a, K: 'a+Ord, V: 'a> Iterator for IterMut<'a, K, V> {
+    type Item = (&'a K, &'a mut V);
+
+    fn next(&mut self) -> Option<(&'a K, &'a mut V)> {
+        self.entries.next().map(|entry| (&entry.key, &mut entry.value))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.entries.size_hint()
+    }
+}
+
+impl <'a, K: 'a+Ord, V: 'a> ExactSizeIterator for IterMut<'a, K, V> {}
+
+pub struct RangeMut<'a, K: 'a+Ord, V: 'a, R: RangeBounds<K>> {
+    entries: SortedIteratorMut<'a, Entry<K, V>>,
+    bounds: R,
+    done: bool
+}
+
+impl <'a, K: 'a+Ord, V: 'a, R: RangeBounds<K>> Iterator for RangeMut<'a, K, V, R> {
+    type Item = (&'a K, &'a mut V);
+
+    fn next(&mut self) -> Option<(&'a K, &'a mut V)> {
+        if self.done {
+            return None
+        }
+        let entry = self.entries.next()?;
+        if !before_end(self.bounds.end_bound(), &entry.key) {
+            self.done = true;
+            return None
+        }
+        Some((&entry.key, &mut entry.value))
+    }
+
+    // Everything from the start of the range on is counted, since where it ends isn't
+    // known until we get there.
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (0, if self.done { Some(0) } else { self.entries.size_hint().1 })
+    }
+}
+
+pub struct Keys<'a, K: 'a+Ord, V: 'a> {
+    entries: SortedIterator<'a, Entry<K, V>>
+}
+
+impl <'a, K: 'a+Ord, V: 'a> Iterator for Keys<'a, K, V> {
+    type Item = &'a K;
+
+    fn next(&mut self) -> Option<&'a K> {
+        self.entries.next().map(|entry| &entry.key)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.entries.size_hint()
+    }
+}
+
+impl <'a, K: 'a+Ord, V: 'a> ExactSizeIterator for Keys<'a, K, V> {}
+
+pub struct Values<'a, K: 'a+Ord, V: 'a> {
+    entries: SortedIterator<'a, Entry<K, V>>
+}
+
+impl <'a, K: 'a+Ord, V: 'a> Iterator for Values<'a, K, V> {
+    type Item = &'a V;
+
+    fn next(&mut self) -> Option<&'a V> {
+        self.entries.next().map(|entry| &entry.value)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.entries.size_hint()
+    }
+}
+
+impl <'a, K: 'a+Ord, V: 'a> ExactSizeIterator for Values<'a, K, V> {}
+
+pub struct ValuesMut<'a, K: 'a+Ord, V: 'a> {
+    entries: SortedIteratorMut<'a, Entry<K, V>>
+}
+
+impl <'a, K: 'a+Ord, V: 'a> Iterator for ValuesMut<'a, K, V> {
+    type Item = &'a mut V;
+
+    fn next(&mut self) -> Option<&'a mut V> {
+        self.entries.next().map(|entry| &mut entry.value)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.entries.size_hint()
+    }
+}
+
+impl <'a, K: 'a+Ord, V: 'a> ExactSizeIterator for ValuesMut<'a, K, V> {}
+
+pub struct IntoKeys<K: Ord, V> {
+    entries: Drain<Entry<K, V>>
+}
+
+impl <K: Ord, V> Iterator for IntoKeys<K, V> {
+    type Item = K;
+
+    fn next(&mut self) -> Option<K> {
+        self.entries.next().map(|entry| entry.key)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.entries.size_hint()
+    }
+}
+
+impl <K: Ord, V> ExactSizeIterator for IntoKeys<K, V> {}
+
+pub struct IntoValues<K: Ord, V> {
+    entries: Drain<Entry<K, V>>
+}
+
+impl <K: Ord, V> Iterator for IntoValues<K, V> {
+    type Item = V;
+
+    fn next(&mut self) -> Option<V> {
+        self.entries.next().map(|entry| entry.value)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.entries.size_hint()
+    }
+}
+
+impl <K: Ord, V> ExactSizeIterator for IntoValues<K, V> {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Inserting and removing arbitrary entries should agree with a BTreeMap about what
+    // every operation returns and about what ends up in the map.
+    #[quickcheck]
+    fn map_property(inserts: Vec<(i32, i32)>, removals: Vec<i32>) -> bool {
+        let mut map = Map::new();
+        let mut reference = std::collections::BTreeMap::new();
+        let inserts_ok = inserts.iter().all(|&(key, value)| map.insert(key, value) == reference.insert(key, value));
+        let removals_ok = removals.iter().all(|key| map.remove(key) == reference.remove(key));
+
+        let entries: Vec<(&i32, &i32)> = map.iter().collect();
+        inserts_ok && removals_ok && map.len() == reference.len() && entries == reference.iter().collect::<Vec<_>>() &&
+            inserts.iter().all(|&(key, _)| map.get(&key) == reference.get(&key)) && map.tree.check_invariants().is_ok()
+    }
+
+    // A map whose values take up no space is an ordered set of markers, like the
+    // positions things were inserted at. Rotations move the entries around with their
+    // keys, and the zero sized values shouldn't trip them up.
+    #[test]
+    fn zero_sized_values() {
+        #[derive(Debug, PartialEq)]
+        struct Unit;
+
+        let mut map = Map::new();
+        for index in (0..100).rev() {
+            assert_eq!(None, map.insert(index, Unit));
+        }
+        assert_eq!(Some(Unit), map.insert(50, Unit));
+        for index in (0..100).filter(|index| index % 3 == 0) {
+            assert_eq!(Some(Unit), map.remove(&index));
+        }
+
+        let keys: Vec<i32> = map.iter().map(|(&key, _)| key).collect();
+        assert_eq!((0..100).filter(|index| index % 3 != 0).collect::<Vec<_>>(), keys);
+        assert!(map.tree.check_invariants().is_ok());
+    }
+
+    // Collecting should agree with a BTreeMap, which also keeps the last value for each
+    // key.
+    #[quickcheck]
+    fn from_iter_property(pairs: Vec<(i32, i32)>) -> bool {
+        let map: Map<i32, i32> = pairs.iter().cloned().collect();
+        let reference: std::collections::BTreeMap<i32, i32> = pairs.into_iter().collect();
+        map.iter().collect::<Vec<_>>() == reference.iter().collect::<Vec<_>>() && map.tree.check_invariants().is_ok()
+    }
+
+    // Building a small map from a Vec of pairs, the way the docs would show it.
+    #[test]
+    fn collect_from_pairs() {
+        let map: Map<&str, u32> = vec![("pear", 2), ("apple", 1), ("cherry", 7)].into_iter().collect();
+        assert_eq!(3, map.len());
+        assert_eq!(Some(&1), map.get(&"apple"));
+        assert_eq!(vec![&"apple", &"cherry", &"pear"], map.keys().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn from_iter_keeps_last_value() {
+        let map: Map<&str, i32> = vec![("b", 1), ("a", 2), ("b", 3), ("c", 4), ("b", 5)].into_iter().collect();
+        assert_eq!(vec![(&"a", &2), (&"b", &5), (&"c", &4)], map.iter().collect::<Vec<_>>());
+    }
+
+    // Kept entries should have their new values, and removed ones should be gone.
+    #[test]
+    fn retain_mut_changes_and_removes() {
+        let mut map: Map<i32, i32> = (0..100).map(|key| (key, key)).collect();
+        map.retain_mut(|&key, value| {
+            *value *= 10;
+            key % 3 != 0
+        });
+
+        let expected: Vec<(i32, i32)> = (0..100).filter(|key| key % 3 != 0).map(|key| (key, key * 10)).collect();
+        assert_eq!(expected, map.iter().map(|(&key, &value)| (key, value)).collect::<Vec<_>>());
+        assert!(!map.contains_key(&30));
+        assert_eq!(expected.len(), map.len());
+        assert!(map.tree.check_invariants().is_ok());
+    }
+
+    // A small map, round tripped through every way of looking at it, the way BTreeMap's
+    // docs would show it.
+    #[test]
+    fn small_map_round_trip() {
+        let mut map = Map::new();
+        map.insert("b", 2);
+        map.insert("c", 3);
+        map.insert("a", 1);
+
+        assert!(map.contains_key(&"a"));
+        assert!(!map.contains_key(&"d"));
+        assert_eq!(Some(&3), map.get(&"c"));
+        assert_eq!(None, map.get(&"d"));
+        assert_eq!(Some((&"b", &2)), map.get_key_value(&"b"));
+        assert_eq!(None, map.get_key_value(&"d"));
+        assert_eq!(vec![&"a", &"b", &"c"], map.keys().collect::<Vec<_>>());
+        assert_eq!(vec![&1, &2, &3], map.values().collect::<Vec<_>>());
+
+        for value in map.values_mut() {
+            *value *= 10;
+        }
+        assert_eq!(3, map.values_mut().len());
+        let entries: Vec<(&str, i32)> = map.iter().map(|(&key, &value)| (key, value)).collect();
+        assert_eq!(vec![("a", 10), ("b", 20), ("c", 30)], entries);
+        assert!(map.tree.check_invariants().is_ok());
+    }
+
+    // Changing every value through iter_mut, holding on to all of them at once, should
+    // leave the keys in order with the new values.
+    #[quickcheck]
+    fn iter_mut_property(keys: Vec<i32>) -> bool {
+        let mut map = Map::new();
+        for &key in &keys {
+            map.insert(key, 0i64);
+        }
+
+        let mut borrowed: Vec<(&i32, &mut i64)> = map.iter_mut().collect();
+        for &mut (&key, ref mut value) in &mut borrowed {
+            **value = key as i64 * 2;
+        }
+        let keys_in_order = borrowed.windows(2).all(|pair| pair[0].0 < pair[1].0);
+
+        keys_in_order && map.iter().all(|(&key, &value)| value == key as i64 * 2) && map.tree.check_invariants().is_ok()
+    }
+
+    // Keys and values should come out by value in key order, and stopping early should
+    // still drop whatever's left.
+    #[test]
+    fn into_keys_and_values_move_out() {
+        let map: Map<String, Box<i32>> = (0..10).map(|key| (format!("{:02}", 9 - key), Box::new(key))).collect();
+        let keys: Vec<String> = map.into_keys().collect();
+        assert_eq!((0..10).map(|key| format!("{:02}", key)).collect::<Vec<_>>(), keys);
+
+        let map: Map<String, Box<i32>> = (0..10).map(|key| (format!("{:02}", 9 - key), Box::new(key))).collect();
+        let values = map.into_values();
+        assert_eq!(10, values.len());
+        assert_eq!((0..10).rev().map(Box::new).collect::<Vec<_>>(), values.collect::<Vec<_>>());
+
+        let shared = std::rc::Rc::new(());
+        let map: Map<i32, std::rc::Rc<()>> = (0..100).map(|key| (key, shared.clone())).collect();
+        let mut values = map.into_values();
+        values.next();
+        drop(values.next());
+        assert_eq!(99, std::rc::Rc::strong_count(&shared));
+        drop(values);
+        assert_eq!(1, std::rc::Rc::strong_count(&shared));
+    }
+
+    // Merging counters should add up the counts of keys in both, and keep the rest as
+    // they were.
+    #[test]
+    fn merge_with_sums_counters() {
+        let mut counts: Map<&str, u32> = vec![("apple", 3), ("cherry", 1), ("pear", 2)].into_iter().collect();
+        let more: Map<&str, u32> = vec![("banana", 5), ("cherry", 4), ("pear", 1), ("quince", 7)].into_iter().collect();
+        let mut collisions = Vec::new();
+        counts.merge_with(more, |&key, existing, incoming| {
+            collisions.push(key);
+            existing + incoming
+        });
+
+        assert_eq!(vec!["cherry", "pear"], collisions);
+        assert_eq!(vec![("apple", 3), ("banana", 5), ("cherry", 5), ("pear", 3), ("quince", 7)],
+                   counts.iter().map(|(&key, &count)| (key, count)).collect::<Vec<_>>());
+        assert!(counts.tree.check_invariants().is_ok());
+    }
+
+    // Changing the values in a range should leave every value outside it alone, and
+    // every kind of bound should pick out the same keys a BTreeMap's would.
+    #[quickcheck]
+    fn range_mut_property(keys: Vec<i32>, start: i32, end: i32, inclusive: bool) -> bool {
+        let mut map: Map<i32, i32> = keys.iter().map(|&key| (key, key)).collect();
+        let mut reference: std::collections::BTreeMap<i32, i32> = keys.iter().map(|&key| (key, key)).collect();
+        if start > end {
+            return map.range_mut(end..).count() == reference.range(end..).count()
+        }
+
+        let bump = |(_, value): (&i32, &mut i32)| *value += 1000;
+        if inclusive {
+            map.range_mut(start..=end).for_each(bump);
+            reference.range_mut(start..=end).for_each(bump);
+        } else {
+            map.range_mut(start..end).for_each(bump);
+            reference.range_mut(start..end).for_each(bump);
+        }
+        map.range_mut(..start).count() == reference.range(..start).count() &&
+            map.iter().map(|(&key, &value)| (key, value)).eq(reference.into_iter())
+    }
+
+    #[test]
+    fn range_mut_borrows_values_separately() {
+        let mut map: Map<i32, i32> = (0..20).map(|key| (key, 0)).collect();
+        {
+            let mut values: Vec<&mut i32> = map.range_mut(5..10).map(|(_, value)| value).collect();
+            let (first, rest) = values.split_first_mut().unwrap();
+            **first = 1;
+            *rest[3] = 2;
+        }
+        assert_eq!(vec![1, 0, 0, 0, 2], map.values().skip(5).take(5).cloned().collect::<Vec<_>>());
+        assert!(map.values().take(5).chain(map.values().skip(10)).all(|&value| value == 0));
+        assert_eq!(0, map.range_mut(100..).count());
+    }
+
+    // Taking the first entry over and over should give the keys in ascending order,
+    // and changes through the last entry should stick.
+    #[test]
+    fn first_and_last_entries() {
+        let mut map: Map<i32, i32> = (0..50).map(|key| ((key * 7) % 50, key)).collect();
+        {
+            let mut last = map.last_entry().unwrap();
+            assert_eq!(49, *last.key());
+            *last.get_mut() += 100;
+            assert_eq!(7 + 100, last.insert(-1));
+        }
+        assert_eq!(Some(&-1), map.get(&49));
+        *map.first_entry().unwrap().into_mut() = -2;
+        assert_eq!(Some(&-2), map.get(&0));
+
+        let mut keys = Vec::new();
+        while let Some(first) = map.first_entry() {
+            keys.push(*first.key());
+            first.remove();
+            assert!(map.tree.check_invariants().is_ok());
+        }
+        assert_eq!((0..50).collect::<Vec<_>>(), keys);
+        assert!(map.last_entry().is_none());
+
+        let mut map: Map<i32, &str> = vec![(1, "one"), (2, "two")].into_iter().collect();
+        assert_eq!((2, "two"), map.last_entry().unwrap().remove_entry());
+        assert_eq!(1, map.len());
+    }
+
+    #[test]
+    fn get_mut_changes_values_in_place() {
+        let mut map = Map::new();
+        for key in 0..100 {
+            map.insert(key, key);
+        }
+
+        for key in 0..100 {
+            *map.get_mut(&key).unwrap() = 100 - key;
+        }
+        assert_eq!(None, map.get_mut(&100));
+
+        let entries: Vec<(i32, i32)> = map.iter().map(|(&key, &value)| (key, value)).collect();
+        assert_eq!((0..100).map(|key| (key, 100 - key)).collect::<Vec<_>>(), entries);
+        assert!(map.tree.check_invariants().is_ok());
+    }
+
+    // Moving keys around should carry their values with them, and refuse to move onto
+    // a key that's taken or from one that isn't there.
+    #[test]
+    fn change_key_moves_entries() {
+        let mut map = Map::new();
+        for key in 0..100 {
+            map.insert(key * 10, key);
+        }
+
+        assert!(map.change_key(&500, -5));
+        assert!(map.change_key(&990, 455));
+        assert!(!map.change_key(&123, 124));
+        assert!(!map.change_key(&0, 10));
+
+        let (keys, values): (Vec<i32>, Vec<i32>) = map.iter().map(|(&key, &value)| (key, value)).unzip();
+        let mut expected: Vec<(i32, i32)> = (0..100).map(|key| (key * 10, key)).filter(|&(key, _)| key != 500 && key != 990).collect();
+        expected.push((-5, 50));
+        expected.push((455, 99));
+        expected.sort();
+
+        assert_eq!(expected.iter().map(|&(key, _)| key).collect::<Vec<_>>(), keys);
+        assert_eq!(expected.iter().map(|&(_, value)| value).collect::<Vec<_>>(), values);
+        assert_eq!(100, map.len());
+        assert!(map.tree.check_invariants().is_ok());
+    }
+}
@@ -0,0 +1,295 @@
+use alloc::rc::Rc;
+use alloc::vec::Vec;
+use core::cmp::max;
+use avl::taller;
+
+// A persistent AVL tree is one where updating the tree doesn't change it, but instead
+// gives you a new tree with the update applied, leaving the old one intact. Copying
+// the whole tree for every update would be far too slow, but it turns out we don't
+// need to: an insert or remove only ever changes the nodes on the path from the root
+// down to where the change happens. So we copy just that path, and the new nodes
+// point at the same, unchanged, subtrees hanging off the path as the old nodes do.
+// That's why the children are Rcs rather than Boxes, since a subtree can belong to
+// any number of versions at once.
+//
+// This is known as path copying. Every update allocates O(log n) new nodes, one per
+// level of the path plus a couple for rotations, and clones the values in them,
+// which is why values have to be Clone. Cloning a whole tree is O(1).
+//
+// The balancing logic is the same as in the mutable tree, except that instead of
+// rotating nodes in place, we build the rotated nodes fresh.
+struct Node<V: Ord> {
+    metadata: (i8, i8),
+    value: V,
+    left: Option<Rc<Node<V>>>,
+    right: Option<Rc<Node<V>>>
+}
+
+pub struct PersistentTree<V: Ord> {
+    root: Option<Rc<Node<V>>>,
+    len: usize
+}
+
+// Cloning just clones the Rc of the root, so the clone shares every node.
+impl <V: Ord> Clone for PersistentTree<V> {
+    fn clone(&self) -> Self {
+        PersistentTree {root: self.root.clone(), len: self.len}
+    }
+}
+
+fn height<V: Ord>(tree: &Option<Rc<Node<V>>>) -> i8 {
+    match *tree {
+        Some(ref tree) => taller(max(tree.metadata.0, tree.metadata.1)),
+        None => 0
+    }
+}
+
+fn node<V: Ord>(left: Option<Rc<Node<V>>>, value: V, right: Option<Rc<Node<V>>>) -> Rc<Node<V>> {
+    Rc::new(Node {metadata: (height(&left), height(&right)), value, left, right})
+}
+
+// The same bottom-up build as the mutable tree's build_sorted: half the values to the
+// left, the middle one at the root, and the rest to the right, so it comes out
+// perfectly balanced without any rotations.
+fn build_sorted<V: Ord, I: Iterator<Item=V>>(values: &mut I, n: usize) -> Option<Rc<Node<V>>> {
+    if n == 0 {
+        return None
+    }
+
+    let left = build_sorted(values, n / 2);
+    let value = values.next().expect("there should be n values left");
+    let right = build_sorted(values, n - n / 2 - 1);
+    Some(node(left, value, right))
+}
+
+// Builds a node out of two subtrees whose heights may differ by up to two, rotating
+// if they do. These are the same four cases as the mutable balance: if the left side
+// is two taller, and its own left side is at least as tall as its right, a single
+// rotation to the right fixes it. Otherwise the left side's right child is the one
+// that's too tall, and it becomes the new root (the double rotation). The right side
+// being too tall is the mirror image.
+fn balance<V: Ord+Clone>(left: Option<Rc<Node<V>>>, value: V, right: Option<Rc<Node<V>>>) -> Rc<Node<V>> {
+    let (left_height, right_height) = (height(&left), height(&right));
+    debug_assert!(left_height - right_height <= 2 && left_height - right_height >= -2);
+
+    if left_height == right_height + 2 {
+        let left = left.unwrap();
+        if height(&left.left) >= height(&left.right) {
+            node(left.left.clone(), left.value.clone(), Some(node(left.right.clone(), value, right)))
+        } else {
+            let inner = left.right.as_ref().unwrap();
+            node(Some(node(left.left.clone(), left.value.clone(), inner.left.clone())),
+                 inner.value.clone(),
+                 Some(node(inner.right.clone(), value, right)))
+        }
+    } else if right_height == left_height + 2 {
+        let right = right.unwrap();
+        if height(&right.right) >= height(&right.left) {
+            node(Some(node(left, value, right.left.clone())), right.value.clone(), right.right.clone())
+        } else {
+            let inner = right.left.as_ref().unwrap();
+            node(Some(node(left, value, inner.left.clone())),
+                 inner.value.clone(),
+                 Some(node(inner.right.clone(), right.value.clone(), right.right.clone())))
+        }
+    } else {
+        node(left, value, right)
+    }
+}
+
+// Returns the new version of the subtree, or None if the value was already in it, in
+// which case nothing needs copying at all.
+fn insert<V: Ord+Clone>(tree: &Option<Rc<Node<V>>>, value: V) -> Option<Rc<Node<V>>> {
+    match *tree {
+        None => Some(node(None, value, None)),
+        Some(ref tree) => {
+            if value < tree.value {
+                insert(&tree.left, value).map(|left| balance(Some(left), tree.value.clone(), tree.right.clone()))
+            } else if value > tree.value {
+                insert(&tree.right, value).map(|right| balance(tree.left.clone(), tree.value.clone(), Some(right)))
+            } else {
+                None
+            }
+        }
+    }
+}
+
+// Returns the new version of the subtree with its smallest value taken out, along with
+// that value.
+fn remove_min<V: Ord+Clone>(tree: &Rc<Node<V>>) -> (Option<Rc<Node<V>>>, V) {
+    match tree.left {
+        None => (tree.right.clone(), tree.value.clone()),
+        Some(ref left) => {
+            let (left, min) = remove_min(left);
+            (Some(balance(left, tree.value.clone(), tree.right.clone())), min)
+        }
+    }
+}
+
+// Returns the new version of the subtree, or None if the value wasn't in it. Removing
+// a node with two children replaces its value with the smallest value from its right
+// subtree, which keeps everything in order.
+fn remove<V: Ord+Clone>(tree: &Option<Rc<Node<V>>>, value: &V) -> Option<Option<Rc<Node<V>>>> {
+    let tree = tree.as_ref()?;
+
+    if *value < tree.value {
+        remove(&tree.left, value).map(|left| Some(balance(left, tree.value.clone(), tree.right.clone())))
+    } else if *value > tree.value {
+        remove(&tree.right, value).map(|right| Some(balance(tree.left.clone(), tree.value.clone(), right)))
+    } else {
+        match (&tree.left, &tree.right) {
+            (None, right) => Some(right.clone()),
+            (left, None) => Some(left.clone()),
+            (left, Some(right)) => {
+                let (right, min) = remove_min(right);
+                Some(Some(balance(left.clone(), min, right)))
+            }
+        }
+    }
+}
+
+impl <V: Ord> Default for PersistentTree<V> {
+    fn default() -> Self {
+        PersistentTree::new()
+    }
+}
+
+impl <V: Ord> PersistentTree<V> {
+    pub fn new() -> Self {
+        PersistentTree {root: None, len: 0}
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn contains(&self, value: &V) -> bool {
+        let mut tree = &self.root;
+        while let Some(ref node) = *tree {
+            if *value < node.value {
+                tree = &node.left;
+            } else if *value > node.value {
+                tree = &node.right;
+            } else {
+                return true
+            }
+        }
+        false
+    }
+
+    // Builds a tree out of len strictly ascending values in O(n).
+    pub(crate) fn from_sorted<I: Iterator<Item=V>>(values: I, len: usize) -> PersistentTree<V> {
+        let mut values = values;
+        PersistentTree {root: build_sorted(&mut values, len), len}
+    }
+
+    pub fn iter_sorted<'a>(&'a self) -> SortedIterator<'a, V> {
+        let mut iter = SortedIterator {stack: Vec::new()};
+        iter.push_left_spine(&self.root);
+        iter
+    }
+}
+
+impl <V: Ord+Clone> PersistentTree<V> {
+    // A new version of the tree with the value in it. If it was already there, the new
+    // version is just another handle on this one.
+    pub fn insert(&self, value: V) -> PersistentTree<V> {
+        match insert(&self.root, value) {
+            Some(root) => PersistentTree {root: Some(root), len: self.len + 1},
+            None => self.clone()
+        }
+    }
+
+    // A new version of the tree without the value in it.
+    pub fn remove(&self, value: &V) -> PersistentTree<V> {
+        match remove(&self.root, value) {
+            Some(root) => PersistentTree {root, len: self.len - 1},
+            None => self.clone()
+        }
+    }
+}
+
+// The same in-order traversal as the mutable tree's SortedIterator.
+pub struct SortedIterator<'a, V: 'a+Ord> {
+    stack: Vec<&'a Node<V>>
+}
+
+impl <'a, V: 'a+Ord> SortedIterator<'a, V> {
+    fn push_left_spine(&mut self, mut next: &'a Option<Rc<Node<V>>>) {
+        while let Some(ref tree) = *next {
+            self.stack.push(tree);
+            next = &tree.left;
+        }
+    }
+}
+
+impl <'a, V: 'a+Ord> Iterator for SortedIterator<'a, V> {
+    type Item = &'a V;
+
+    fn next(&mut self) -> Option<&'a V> {
+        let tree = self.stack.pop()?;
+        self.push_left_spine(&tree.right);
+        Some(&tree.value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Building trees up version by version, and removing from them, should never change
+    // any earlier version, and every version should be a valid AVL tree holding exactly
+    // what was put into it.
+    #[quickcheck]
+    fn persistence_property(values: Vec<i32>, removals: Vec<i32>) -> bool {
+        let mut versions = vec![PersistentTree::new()];
+        for &value in &values {
+            let next = versions.last().unwrap().insert(value);
+            versions.push(next);
+        }
+
+        let mut removed = versions.last().unwrap().clone();
+        for value in &removals {
+            removed = removed.remove(value);
+        }
+
+        let mut expected: Vec<i32> = Vec::new();
+        let versions_ok = versions.iter().zip(0..).all(|(version, i)| {
+            if i > 0 && !expected.contains(&values[i - 1]) {
+                expected.push(values[i - 1]);
+                expected.sort();
+            }
+            valid(&version.root) && version.len() == expected.len() && version.iter_sorted().cloned().collect::<Vec<_>>() == expected
+        });
+
+        let remaining: Vec<i32> = expected.iter().cloned().filter(|value| !removals.contains(value)).collect();
+        versions_ok && valid(&removed.root) && removed.len() == remaining.len() &&
+            removed.iter_sorted().cloned().collect::<Vec<_>>() == remaining
+    }
+
+    // Building straight from sorted values should give a valid tree too.
+    #[quickcheck]
+    fn from_sorted_property(values: Vec<i32>) -> bool {
+        let mut values = values;
+        values.sort();
+        values.dedup();
+        let tree = PersistentTree::from_sorted(values.iter().cloned(), values.len());
+        valid(&tree.root) && tree.len() == values.len() && tree.iter_sorted().cloned().collect::<Vec<_>>() == values
+    }
+
+    fn valid(tree: &Option<Rc<Node<i32>>>) -> bool {
+        match *tree {
+            Some(ref node) => {
+                node.metadata == (height(&node.left), height(&node.right)) &&
+                    node.metadata.0 - node.metadata.1 <= 1 && node.metadata.0 - node.metadata.1 >= -1 &&
+                    node.left.iter().all(|left| left.value < node.value && valid(&node.left)) &&
+                    node.right.iter().all(|right| right.value > node.value && valid(&node.right))
+            }
+            None => true
+        }
+    }
+}
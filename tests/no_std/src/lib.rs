@@ -0,0 +1,35 @@
+#![no_std]
+
+extern crate alloc;
+extern crate dadabass;
+
+use alloc::vec::Vec;
+use core::panic::PanicInfo;
+use dadabass::arena::ArenaTree;
+use dadabass::avl::Tree;
+use dadabass::map::Map;
+use dadabass::persistent::PersistentTree;
+
+// Something from each of the trees that's there without std, so that any of them
+// reaching for std by mistake breaks this build.
+pub fn use_the_trees(values: &[u32]) -> usize {
+    let mut tree = Tree::new();
+    let mut arena = ArenaTree::new();
+    let mut map = Map::new();
+    let mut persistent = PersistentTree::new();
+    for &value in values {
+        tree.insert(value);
+        arena.insert(value);
+        map.insert(value, value * 2);
+        persistent = persistent.insert(value);
+    }
+    let sorted: Vec<&u32> = tree.iter_sorted().collect();
+    sorted.len() + arena.len() + map.len() + persistent.len()
+}
+
+// Only one crate in a build can define the panic handler, and std already does, so
+// this is what fails if std sneaks in.
+#[panic_handler]
+fn panic(_: &PanicInfo) -> ! {
+    loop {}
+}